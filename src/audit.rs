@@ -0,0 +1,13 @@
+use crate::auth::Identity;
+
+// Security relevant events, logged under the "audit" target so they can be filtered or routed on their own
+pub fn record(event: &str, identity: Option<&Identity>, detail: &str) {
+    tracing::info!(
+        target: "audit",
+        event,
+        identity = identity.map(|i| i.name.as_str()),
+        source = identity.map(|i| i.source.as_str()),
+        detail,
+        "audit"
+    );
+}
@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::fs;
+
+use axum::http::StatusCode;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header};
+use serde::Deserialize;
+
+use super::{Identity, IdentitySource, Scopes};
+use crate::error::PledgeError;
+
+#[derive(Debug, Deserialize)]
+pub struct JwtConfig {
+    pub secret: Option<String>,             // Shared secret for HS256/384/512
+    pub jwks_path: Option<String>,          // Local JWKS file for asymmetric keys
+    pub algorithms: Option<Vec<Algorithm>>, // Defaults to HS256 with a secret, RS256 with a JWKS
+    pub audience: Option<String>,
+    pub issuer: Option<String>,
+    pub name_claim: Option<String>, // Claim used as the identity name, defaults to "sub"
+    pub role_claim: Option<String>, // Claim holding the Postgres role for auth.rls, defaults to "role"
+    pub role: Option<String>,       // Role for tokens without the role claim
+    #[serde(flatten)]
+    pub scopes: Scopes,
+}
+
+// Server side binding of a verified claim to a `$n` parameter, the client can't override it
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClaimBinding {
+    pub param: usize,  // 1-based, as in $1, the template's SQL must use it
+    pub claim: String, // Dotted path for nested claims, e.g. "app_metadata.tenant_id"
+}
+
+pub struct JwtVerifier {
+    secret: Option<DecodingKey>,
+    jwks: HashMap<String, DecodingKey>, // By kid
+    algorithms: Vec<Algorithm>,
+    audience: Option<String>,
+    issuer: Option<String>,
+    name_claim: String,
+    role_claim: String,
+    role: Option<String>,
+    scopes: Scopes,
+}
+
+impl JwtVerifier {
+    pub fn new(config: &JwtConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let secret = config
+            .secret
+            .as_ref()
+            .map(|secret| DecodingKey::from_secret(secret.as_bytes()));
+
+        let mut jwks = HashMap::new();
+        if let Some(path) = &config.jwks_path {
+            let set: JwkSet = serde_json::from_str(&fs::read_to_string(path)?)?;
+            for jwk in &set.keys {
+                let kid = jwk.common.key_id.clone().unwrap_or_default();
+                jwks.insert(kid, DecodingKey::from_jwk(jwk)?);
+            }
+        }
+        if secret.is_none() && jwks.is_empty() {
+            return Err("[auth.jwt] needs a secret or a jwks_path with at least one key".into());
+        }
+
+        let algorithms = match &config.algorithms {
+            Some(algorithms) => algorithms.clone(),
+            None if secret.is_some() => vec![Algorithm::HS256],
+            None => vec![Algorithm::RS256],
+        };
+
+        Ok(JwtVerifier {
+            secret,
+            jwks,
+            algorithms,
+            audience: config.audience.clone(),
+            issuer: config.issuer.clone(),
+            name_claim: config.name_claim.clone().unwrap_or("sub".to_string()),
+            role_claim: config.role_claim.clone().unwrap_or("role".to_string()),
+            role: config.role.clone(),
+            scopes: config.scopes.clone(),
+        })
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Identity, PledgeError> {
+        let header = decode_header(token).map_err(|_| invalid_token("Malformed JWT"))?;
+        if !self.algorithms.contains(&header.alg) {
+            return Err(invalid_token("JWT algorithm not allowed"));
+        }
+
+        let key = match (&header.kid, &self.secret) {
+            (Some(kid), _) if self.jwks.contains_key(kid) => &self.jwks[kid],
+            (_, Some(secret)) => secret,
+            // A JWKS with a single key doesn't need the token to name it
+            (None, None) if self.jwks.len() == 1 => self.jwks.values().next().unwrap(),
+            _ => return Err(invalid_token("Unknown JWT signing key")),
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = self.algorithms.clone();
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+
+        let data = decode::<serde_json::Value>(token, key, &validation)
+            .map_err(|e| invalid_token(&format!("Invalid JWT: {}", e)))?;
+        let name = match claim(&data.claims, &self.name_claim) {
+            Some(serde_json::Value::String(name)) => name.clone(),
+            Some(other) => other.to_string(),
+            None => "jwt".to_string(),
+        };
+        let role = match claim(&data.claims, &self.role_claim) {
+            Some(serde_json::Value::String(role)) => Some(role.clone()),
+            _ => self.role.clone(),
+        };
+
+        Ok(Identity {
+            name,
+            source: IdentitySource::Jwt,
+            scopes: self.scopes.clone(),
+            role,
+            claims: Some(data.claims),
+        })
+    }
+}
+
+pub fn claim<'a>(claims: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    path.split('.')
+        .try_fold(claims, |value, segment| value.get(segment))
+}
+
+// Overwrites the bound parameters with the caller's claims
+pub fn bind_claims(
+    bindings: &[ClaimBinding],
+    identity: Option<&Identity>,
+    params: &[serde_json::Value],
+) -> Result<Vec<serde_json::Value>, PledgeError> {
+    let Some(claims) = identity.and_then(|i| i.claims.as_ref()) else {
+        return Err(PledgeError::new(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "This query binds JWT claims and needs a JWT",
+        ));
+    };
+
+    let mut params = params.to_vec();
+    for binding in bindings {
+        let Some(value) = claim(claims, &binding.claim) else {
+            return Err(PledgeError::new(
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                format!("JWT is missing the '{}' claim", binding.claim),
+            ));
+        };
+        if params.len() < binding.param {
+            params.resize(binding.param, serde_json::Value::Null);
+        }
+        params[binding.param - 1] = value.clone();
+    }
+    Ok(params)
+}
+
+fn invalid_token(message: &str) -> PledgeError {
+    PledgeError::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use base64::Engine as _;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use jsonwebtoken::{EncodingKey, Header, encode};
+    use serde_json::json;
+
+    use super::*;
+
+    const SECRET: &str = "test-secret";
+
+    fn config(toml: &str) -> JwtConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn token(header: Header, claims: serde_json::Value, secret: &str) -> String {
+        encode(
+            &header,
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    fn claims() -> serde_json::Value {
+        json!({ "sub": "user-1", "aud": "pledge", "exp": now() + 60, "role": "web_user" })
+    }
+
+    fn verifier() -> JwtVerifier {
+        JwtVerifier::new(&config(&format!(
+            "secret = \"{}\"\naudience = \"pledge\"",
+            SECRET
+        )))
+        .unwrap()
+    }
+
+    fn status(result: Result<Identity, PledgeError>) -> StatusCode {
+        result.map(|_| StatusCode::OK).unwrap_or_else(|e| e.status)
+    }
+
+    #[test]
+    fn valid_token_becomes_an_identity() {
+        let identity = verifier()
+            .verify(&token(Header::default(), claims(), SECRET))
+            .unwrap();
+        assert_eq!(identity.name, "user-1");
+        assert_eq!(identity.source, IdentitySource::Jwt);
+        assert_eq!(identity.role.as_deref(), Some("web_user"));
+        assert_eq!(identity.claims.unwrap()["sub"], "user-1");
+    }
+
+    #[test]
+    fn rejects_wrong_algorithm() {
+        let token = token(Header::new(Algorithm::HS512), claims(), SECRET);
+        assert_eq!(status(verifier().verify(&token)), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn rejects_wrong_signature() {
+        let token = token(Header::default(), claims(), "other-secret");
+        assert_eq!(status(verifier().verify(&token)), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        let mut claims = claims();
+        claims["exp"] = json!(now() - 3600);
+        let token = token(Header::default(), claims, SECRET);
+        assert_eq!(status(verifier().verify(&token)), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn rejects_wrong_audience() {
+        let mut claims = claims();
+        claims["aud"] = json!("someone-else");
+        let token = token(Header::default(), claims, SECRET);
+        assert_eq!(status(verifier().verify(&token)), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn picks_the_jwks_key_by_kid() {
+        let path = std::env::temp_dir().join(format!("pledge-jwks-{}.json", std::process::id()));
+        let jwks = json!({ "keys": [
+            { "kty": "oct", "kid": "a", "k": URL_SAFE_NO_PAD.encode("secret-a") },
+            { "kty": "oct", "kid": "b", "k": URL_SAFE_NO_PAD.encode("secret-b") },
+        ]});
+        fs::write(&path, jwks.to_string()).unwrap();
+        let verifier = JwtVerifier::new(&config(&format!(
+            "jwks_path = \"{}\"\nalgorithms = [\"HS256\"]\naudience = \"pledge\"",
+            path.display()
+        )))
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let with_kid = |kid: Option<&str>| Header {
+            kid: kid.map(str::to_string),
+            ..Header::default()
+        };
+        let signed_b = token(with_kid(Some("b")), claims(), "secret-b");
+        assert!(verifier.verify(&signed_b).is_ok());
+        let signed_a_named_b = token(with_kid(Some("b")), claims(), "secret-a");
+        assert!(verifier.verify(&signed_a_named_b).is_err());
+        let unknown_kid = token(with_kid(Some("c")), claims(), "secret-a");
+        assert!(verifier.verify(&unknown_kid).is_err());
+        // With several keys the token has to name one
+        let no_kid = token(with_kid(None), claims(), "secret-a");
+        assert!(verifier.verify(&no_kid).is_err());
+    }
+
+    #[test]
+    fn bound_claims_overwrite_params() {
+        let identity = verifier()
+            .verify(&token(Header::default(), claims(), SECRET))
+            .unwrap();
+        let bindings = vec![ClaimBinding {
+            param: 2,
+            claim: "sub".to_string(),
+        }];
+
+        let params = bind_claims(&bindings, Some(&identity), &[json!(10), json!("other")]);
+        assert_eq!(params.unwrap(), vec![json!(10), json!("user-1")]);
+        // Missing params are filled up to the bound one
+        let params = bind_claims(&bindings, Some(&identity), &[]);
+        assert_eq!(params.unwrap(), vec![json!(null), json!("user-1")]);
+    }
+
+    #[test]
+    fn bound_claims_need_a_jwt_with_the_claim() {
+        let bindings = vec![ClaimBinding {
+            param: 1,
+            claim: "app.tenant_id".to_string(),
+        }];
+        let err = bind_claims(&bindings, None, &[]).unwrap_err();
+        assert_eq!(err.status, StatusCode::UNAUTHORIZED);
+
+        let mut identity = verifier()
+            .verify(&token(Header::default(), claims(), SECRET))
+            .unwrap();
+        let err = bind_claims(&bindings, Some(&identity), &[]).unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+
+        identity.claims = Some(json!({ "app": { "tenant_id": 7 } }));
+        let params = bind_claims(&bindings, Some(&identity), &[json!(1)]).unwrap();
+        assert_eq!(params, vec![json!(7)]);
+    }
+}
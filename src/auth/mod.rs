@@ -0,0 +1,487 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+pub mod jwt;
+
+use crate::audit;
+use crate::cache::QueryTemplate;
+use crate::config::{ApiKeyConfig, AuthConfig, ClientCertConfig};
+use crate::database::session::Session;
+use crate::database::statement;
+use crate::error::PledgeError;
+use crate::server::state::AppState;
+use crate::server::tls::ClientCert;
+use jwt::JwtVerifier;
+
+// What an identity may run. Admins may run anything.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Scopes {
+    #[serde(default)]
+    pub templates: Vec<String>, // Template names, "*" for all templates
+    #[serde(default)]
+    pub tags: Vec<String>, // Any template carrying one of these tags
+    #[serde(default)]
+    pub allow_ad_hoc: bool, // SQL that doesn't match a template
+    #[serde(default)]
+    pub allow_writes: bool,
+    #[serde(default)]
+    pub admin: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IdentitySource {
+    ApiKey,
+    Jwt,
+    ClientCert,
+}
+
+impl IdentitySource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IdentitySource::ApiKey => "api_key",
+            IdentitySource::Jwt => "jwt",
+            IdentitySource::ClientCert => "client_cert",
+        }
+    }
+}
+
+// The authenticated caller, stored in the request extensions by `authenticate`
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub name: String,
+    pub source: IdentitySource,
+    pub scopes: Scopes,
+    pub role: Option<String>, // Postgres role used with auth.rls
+    pub claims: Option<serde_json::Value>, // Verified JWT claims
+}
+
+impl Identity {
+    pub fn authorize(
+        &self,
+        template: Option<&QueryTemplate>,
+        sql: &str,
+    ) -> Result<(), PledgeError> {
+        if self.scopes.admin {
+            return Ok(());
+        }
+
+        let write = match template {
+            Some(template) => !template.is_read_only(),
+            None => !statement::is_read_only(sql),
+        };
+        if write && !self.scopes.allow_writes {
+            return Err(self.deny("writes"));
+        }
+
+        match template {
+            Some(template) => {
+                let by_name = self
+                    .scopes
+                    .templates
+                    .iter()
+                    .any(|name| name == "*" || *name == template.name);
+                let by_tag = template
+                    .tags
+                    .iter()
+                    .any(|tag| self.scopes.tags.contains(tag));
+                if by_name || by_tag {
+                    Ok(())
+                } else {
+                    Err(self.deny(&format!("query '{}'", template.name)))
+                }
+            }
+            None if self.scopes.allow_ad_hoc => Ok(()),
+            None => Err(self.deny("ad-hoc SQL")),
+        }
+    }
+
+    fn deny(&self, what: &str) -> PledgeError {
+        audit::record("forbidden", Some(self), what);
+        PledgeError::new(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            format!("'{}' is not allowed to run {}", self.name, what),
+        )
+    }
+}
+
+// Identities without allow_writes run ad-hoc SQL in a READ ONLY transaction. The keyword check in
+// `authorize` only catches obvious writes, not ones made by functions, e.g. `SELECT some_writing_fn()`.
+pub fn requires_read_only(identity: Option<&Identity>) -> bool {
+    identity.is_some_and(|identity| !identity.scopes.admin && !identity.scopes.allow_writes)
+}
+
+// For endpoints that expose more than query results, e.g. /metrics
+pub fn require_admin(identity: Option<&Identity>) -> Result<(), PledgeError> {
+    match identity {
+        Some(identity) if !identity.scopes.admin => Err(identity.deny("admin endpoints")),
+        _ => Ok(()),
+    }
+}
+
+pub struct Authenticator {
+    enabled: bool,
+    api_keys: HashMap<String, ApiKeyConfig>, // By SHA-256 hex digest of the secret
+    jwt: Option<JwtVerifier>,
+    client_certs: Vec<ClientCertConfig>,
+    rls: bool,
+}
+
+#[derive(Deserialize)]
+struct KeysFile {
+    #[serde(default)]
+    api_keys: Vec<ApiKeyConfig>,
+}
+
+impl Authenticator {
+    pub fn new(config: Option<&AuthConfig>) -> Result<Self, Box<dyn std::error::Error>> {
+        let Some(config) = config else {
+            return Ok(Authenticator {
+                enabled: false,
+                api_keys: HashMap::new(),
+                jwt: None,
+                client_certs: Vec::new(),
+                rls: false,
+            });
+        };
+
+        let mut keys = config.api_keys.clone();
+        if let Some(path) = &config.keys_file {
+            let file: KeysFile = toml::from_str(&fs::read_to_string(path)?)?;
+            keys.extend(file.api_keys);
+        }
+
+        let mut api_keys = HashMap::new();
+        for key in keys {
+            api_keys.insert(key.key_sha256.to_ascii_lowercase(), key);
+        }
+        let jwt = match &config.jwt {
+            Some(jwt) => Some(JwtVerifier::new(jwt)?),
+            None => None,
+        };
+        info!(
+            "Authentication enabled with {} API keys{}",
+            api_keys.len(),
+            if jwt.is_some() { " and JWTs" } else { "" }
+        );
+        Ok(Authenticator {
+            enabled: config.enabled.unwrap_or(true),
+            api_keys,
+            jwt,
+            client_certs: config.client_certs.clone(),
+            rls: config.rls.unwrap_or(false),
+        })
+    }
+
+    // Without auth configured there is no identity and every request is allowed. Under auth.rls only
+    // admins may run ad-hoc SQL, anyone else could override the session with set_config().
+    pub fn authorize(
+        &self,
+        identity: Option<&Identity>,
+        template: Option<&QueryTemplate>,
+        sql: &str,
+    ) -> Result<(), PledgeError> {
+        let Some(identity) = identity else {
+            return Ok(());
+        };
+        identity.authorize(template, sql)?;
+        if self.rls && template.is_none() && !identity.scopes.admin {
+            return Err(identity.deny("ad-hoc SQL under row-level security"));
+        }
+        Ok(())
+    }
+
+    // The Postgres settings a query runs with under auth.rls, None runs it as the pool's own role
+    pub fn session(&self, identity: Option<&Identity>) -> Option<Session> {
+        let identity = identity.filter(|_| self.rls)?;
+        let session = Session {
+            role: identity.role.clone(),
+            claims: identity.claims.as_ref().map(|claims| claims.to_string()),
+        };
+        (session.role.is_some() || session.claims.is_some()).then_some(session)
+    }
+
+    // Credentials in the headers take precedence over the connection's client certificate
+    fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        client_cert: Option<&ClientCert>,
+    ) -> Result<Identity, PledgeError> {
+        let Some(credential) = credential_from_headers(headers) else {
+            return match client_cert {
+                Some(cert) => self.authenticate_client_cert(cert),
+                None => Err(unauthorized("Missing API key or JWT")),
+            };
+        };
+
+        // Bearer tokens that look like a JWT (header.payload.signature) are verified as one
+        if let Credential::Bearer(token) = credential
+            && token.split('.').count() == 3
+            && let Some(jwt) = &self.jwt
+        {
+            return jwt.verify(token);
+        }
+
+        let (Credential::ApiKey(secret) | Credential::Bearer(secret)) = credential;
+        match self.api_keys.get(&sha256_hex(secret)) {
+            Some(key) => Ok(Identity {
+                name: key.name.clone(),
+                source: IdentitySource::ApiKey,
+                scopes: key.scopes.clone(),
+                role: key.role.clone(),
+                claims: None,
+            }),
+            None => Err(unauthorized("Invalid API key")),
+        }
+    }
+
+    fn authenticate_client_cert(&self, cert: &ClientCert) -> Result<Identity, PledgeError> {
+        let mapped = self
+            .client_certs
+            .iter()
+            .find(|config| cert.names.contains(&config.subject));
+        match mapped {
+            Some(config) => Ok(Identity {
+                name: config.name.clone(),
+                source: IdentitySource::ClientCert,
+                scopes: config.scopes.clone(),
+                role: config.role.clone(),
+                claims: None,
+            }),
+            None => Err(unauthorized(&format!(
+                "Client certificate for {} is not mapped to an identity",
+                cert.names
+                    .first()
+                    .map_or("an unnamed subject", String::as_str)
+            ))),
+        }
+    }
+}
+
+// Middleware for every route but /health
+pub async fn authenticate(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if !state.auth.enabled {
+        return next.run(request).await;
+    }
+    let client_cert = request.extensions().get::<ClientCert>();
+    match state.auth.authenticate(request.headers(), client_cert) {
+        Ok(identity) => {
+            request.extensions_mut().insert(Arc::new(identity));
+            next.run(request).await
+        }
+        Err(err) => {
+            audit::record("unauthorized", None, &err.body.message);
+            let mut response = err.into_response();
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+            response
+        }
+    }
+}
+
+enum Credential<'a> {
+    ApiKey(&'a str),
+    Bearer(&'a str), // An API key or a JWT
+}
+
+fn credential_from_headers(headers: &HeaderMap) -> Option<Credential<'_>> {
+    if let Some(key) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+        return Some(Credential::ApiKey(key.trim()));
+    }
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|token| Credential::Bearer(token.trim()))
+}
+
+pub fn sha256_hex(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn unauthorized(message: &str) -> PledgeError {
+    PledgeError::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(scopes: Scopes) -> Identity {
+        Identity {
+            name: "test".to_string(),
+            source: IdentitySource::ApiKey,
+            scopes,
+            role: None,
+            claims: None,
+        }
+    }
+
+    fn template(name: &str, sql: &str) -> QueryTemplate {
+        toml::from_str(&format!(
+            "name = \"{}\"\nsql = \"{}\"\ntags = [\"public\"]",
+            name, sql
+        ))
+        .unwrap()
+    }
+
+    fn authenticator(rls: bool) -> Authenticator {
+        Authenticator {
+            enabled: true,
+            api_keys: HashMap::new(),
+            jwt: None,
+            client_certs: Vec::new(),
+            rls,
+        }
+    }
+
+    #[test]
+    fn admin_may_run_anything() {
+        let admin = identity(Scopes {
+            admin: true,
+            ..Scopes::default()
+        });
+        let insert = template("insert_user", "INSERT INTO users (name) VALUES ($1)");
+        assert!(admin.authorize(Some(&insert), &insert.sql).is_ok());
+        assert!(admin.authorize(None, "DELETE FROM users").is_ok());
+    }
+
+    #[test]
+    fn templates_are_allowed_by_name_or_tag() {
+        let get_user = template("get_user", "SELECT * FROM users WHERE id = $1");
+        let by_name = identity(Scopes {
+            templates: vec!["get_user".to_string()],
+            ..Scopes::default()
+        });
+        let by_wildcard = identity(Scopes {
+            templates: vec!["*".to_string()],
+            ..Scopes::default()
+        });
+        let by_tag = identity(Scopes {
+            tags: vec!["public".to_string()],
+            ..Scopes::default()
+        });
+        let other = identity(Scopes {
+            templates: vec!["get_posts".to_string()],
+            tags: vec!["internal".to_string()],
+            ..Scopes::default()
+        });
+
+        for allowed in [&by_name, &by_wildcard, &by_tag] {
+            assert!(allowed.authorize(Some(&get_user), &get_user.sql).is_ok());
+        }
+        let err = other.authorize(Some(&get_user), &get_user.sql).unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn writes_need_allow_writes() {
+        let insert = template("insert_user", "INSERT INTO users (name) VALUES ($1)");
+        let reader = identity(Scopes {
+            templates: vec!["*".to_string()],
+            allow_ad_hoc: true,
+            ..Scopes::default()
+        });
+        let writer = identity(Scopes {
+            allow_writes: true,
+            ..reader.scopes.clone()
+        });
+
+        let err = reader.authorize(Some(&insert), &insert.sql).unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        assert!(err.body.message.contains("writes"));
+        assert!(reader.authorize(None, "DELETE FROM users").is_err());
+        assert!(reader.authorize(None, "SELECT 1").is_ok());
+
+        assert!(writer.authorize(Some(&insert), &insert.sql).is_ok());
+        assert!(writer.authorize(None, "DELETE FROM users").is_ok());
+    }
+
+    #[test]
+    fn ad_hoc_needs_allow_ad_hoc() {
+        let templates_only = identity(Scopes {
+            templates: vec!["*".to_string()],
+            ..Scopes::default()
+        });
+        let ad_hoc = identity(Scopes {
+            allow_ad_hoc: true,
+            ..Scopes::default()
+        });
+        assert!(templates_only.authorize(None, "SELECT 1").is_err());
+        assert!(ad_hoc.authorize(None, "SELECT 1").is_ok());
+    }
+
+    #[test]
+    fn rls_allows_ad_hoc_sql_to_admins_only() {
+        let ad_hoc = identity(Scopes {
+            allow_ad_hoc: true,
+            ..Scopes::default()
+        });
+        let admin = identity(Scopes {
+            admin: true,
+            ..Scopes::default()
+        });
+        let get_user = template("get_user", "SELECT * FROM users WHERE id = $1");
+        let by_tag = identity(Scopes {
+            tags: vec!["public".to_string()],
+            ..Scopes::default()
+        });
+
+        let sql = "SELECT set_config('role', 'postgres', true)";
+        assert!(
+            authenticator(false)
+                .authorize(Some(&ad_hoc), None, sql)
+                .is_ok()
+        );
+        assert!(
+            authenticator(true)
+                .authorize(Some(&ad_hoc), None, sql)
+                .is_err()
+        );
+        assert!(
+            authenticator(true)
+                .authorize(Some(&admin), None, sql)
+                .is_ok()
+        );
+        assert!(
+            authenticator(true)
+                .authorize(Some(&by_tag), Some(&get_user), &get_user.sql)
+                .is_ok()
+        );
+        assert!(authenticator(true).authorize(None, None, sql).is_ok());
+    }
+
+    #[test]
+    fn read_only_unless_writes_are_allowed() {
+        let reader = identity(Scopes::default());
+        let writer = identity(Scopes {
+            allow_writes: true,
+            ..Scopes::default()
+        });
+        let admin = identity(Scopes {
+            admin: true,
+            ..Scopes::default()
+        });
+        assert!(requires_read_only(Some(&reader)));
+        assert!(!requires_read_only(Some(&writer)));
+        assert!(!requires_read_only(Some(&admin)));
+        assert!(!requires_read_only(None));
+    }
+}
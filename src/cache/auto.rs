@@ -0,0 +1,157 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::cache::store::CacheEntry;
+use crate::config::AutoCacheConfig;
+use crate::database::statement;
+use crate::stats::FingerprintUsage;
+
+// Stands in for the template name on entries cached by auto_cache, so templates that write can
+// list it in `invalidates`
+pub const AUTO_TEMPLATE: &str = "auto";
+
+pub struct AutoCache {
+    min_calls: u64,
+    min_duration_ms: f64,
+    pub ttl: Duration,
+    max_bytes: u64,
+    bytes: AtomicU64, // Size of the auto entries currently in the cache
+}
+
+impl AutoCache {
+    pub fn new(config: &AutoCacheConfig, cache_size: u64) -> Self {
+        let share = config.max_share.unwrap_or(0.1).clamp(0.0, 1.0);
+        AutoCache {
+            min_calls: config.min_calls.unwrap_or(10),
+            min_duration_ms: config.min_duration_ms.unwrap_or(100) as f64,
+            ttl: Duration::from_secs(config.ttl.unwrap_or(5)),
+            max_bytes: (cache_size as f64 * share) as u64,
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    // Only reads whose result doesn't depend on when they run
+    pub fn is_candidate(&self, sql: &str) -> bool {
+        statement::is_read_only(sql) && !statement::is_volatile(sql)
+    }
+
+    // Admits a result once its statement was called often enough and was slow on average, as long
+    // as the auto entries stay within their share of the cache
+    pub fn admit(&self, usage: &FingerprintUsage, bytes: usize) -> bool {
+        if usage.calls < self.min_calls || usage.avg_ms < self.min_duration_ms {
+            return false;
+        }
+        self.bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                let total = current + bytes as u64;
+                (total <= self.max_bytes).then_some(total)
+            })
+            .is_ok()
+    }
+
+    // Called from the eviction listener for every entry that left the cache or was replaced
+    pub fn release(&self, entry: &CacheEntry) {
+        if entry.template == AUTO_TEMPLATE {
+            self.bytes
+                .fetch_sub(entry.data.len() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use moka::sync::Cache;
+
+    use super::*;
+
+    // 100 bytes of a 1000 byte cache, for statements called twice and taking 10ms on average
+    fn auto_cache() -> AutoCache {
+        let config: AutoCacheConfig =
+            toml::from_str("min_calls = 2\nmin_duration_ms = 10\nmax_share = 0.1").unwrap();
+        AutoCache::new(&config, 1000)
+    }
+
+    fn usage(calls: u64, avg_ms: f64) -> FingerprintUsage {
+        FingerprintUsage { calls, avg_ms }
+    }
+
+    fn entry(template: &str, bytes: usize) -> CacheEntry {
+        CacheEntry {
+            data: vec![0; bytes],
+            stored_at: Instant::now(),
+            expires_at: Instant::now() + Duration::from_secs(60),
+            etag: String::new(),
+            template: template.to_string(),
+        }
+    }
+
+    fn bytes(auto: &AutoCache) -> u64 {
+        auto.bytes.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn admits_frequent_slow_statements_only() {
+        let auto = auto_cache();
+        assert!(!auto.admit(&usage(1, 50.0), 10));
+        assert!(!auto.admit(&usage(5, 5.0), 10));
+        assert_eq!(bytes(&auto), 0);
+        assert!(auto.admit(&usage(2, 10.0), 10));
+        assert_eq!(bytes(&auto), 10);
+    }
+
+    #[test]
+    fn stays_within_its_share_of_the_cache() {
+        let auto = auto_cache();
+        assert!(auto.admit(&usage(2, 10.0), 60));
+        assert!(!auto.admit(&usage(2, 10.0), 50));
+        assert!(auto.admit(&usage(2, 10.0), 40));
+        assert!(!auto.admit(&usage(2, 10.0), 1));
+        assert_eq!(bytes(&auto), 100);
+    }
+
+    #[test]
+    fn release_only_counts_auto_entries() {
+        let auto = auto_cache();
+        assert!(auto.admit(&usage(2, 10.0), 30));
+        auto.release(&entry("get_user", 30));
+        assert_eq!(bytes(&auto), 30);
+        auto.release(&entry(AUTO_TEMPLATE, 30));
+        assert_eq!(bytes(&auto), 0);
+    }
+
+    // Wired up like the cache in main.rs
+    #[test]
+    fn replaced_and_invalidated_entries_free_their_bytes() {
+        let auto = Arc::new(auto_cache());
+        let released = auto.clone();
+        let cache: Cache<String, CacheEntry> = Cache::builder()
+            .max_capacity(1000)
+            .eviction_listener(move |_key, value: CacheEntry, _cause| released.release(&value))
+            .build();
+
+        assert!(auto.admit(&usage(2, 10.0), 40));
+        cache.insert("a".to_string(), entry(AUTO_TEMPLATE, 40));
+        // A re-fetch is admitted before it replaces the old entry
+        assert!(auto.admit(&usage(3, 10.0), 50));
+        cache.insert("a".to_string(), entry(AUTO_TEMPLATE, 50));
+        cache.run_pending_tasks();
+        assert_eq!(bytes(&auto), 50);
+
+        cache.invalidate("a");
+        cache.run_pending_tasks();
+        assert_eq!(bytes(&auto), 0);
+        assert!(auto.admit(&usage(2, 10.0), 100));
+    }
+
+    #[test]
+    fn volatile_and_writing_statements_are_not_candidates() {
+        let auto = auto_cache();
+        assert!(auto.is_candidate("SELECT * FROM users WHERE id = $1"));
+        assert!(!auto.is_candidate("SELECT now(), id FROM users"));
+        assert!(!auto.is_candidate("SELECT random()"));
+        assert!(!auto.is_candidate("UPDATE users SET name = $1"));
+    }
+}
@@ -0,0 +1,111 @@
+use std::time::{Duration, Instant};
+
+use axum::http::{HeaderMap, header};
+use serde::Deserialize;
+
+use super::store::CacheEntry;
+
+// Client supplied cache directives, either from the request body or the Cache-Control header
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct CacheDirectives {
+    #[serde(default)]
+    pub bypass: bool, // Neither read nor write the cache
+    pub max_age: Option<u64>, // Oldest cached result (in seconds) the client will accept
+    #[serde(default)]
+    pub refresh: bool, // Skip the cached result but store the fresh one
+    #[serde(default)]
+    pub only_if_cached: bool, // Never go to the database
+}
+
+impl CacheDirectives {
+    // Body directives take precedence over the Cache-Control header
+    pub fn resolve(headers: &HeaderMap, body: Option<CacheDirectives>) -> Self {
+        let from_header = headers
+            .get(header::CACHE_CONTROL)
+            .and_then(|value| value.to_str().ok())
+            .map(Self::from_cache_control)
+            .unwrap_or_default();
+
+        match body {
+            Some(body) => CacheDirectives {
+                bypass: body.bypass || from_header.bypass,
+                max_age: body.max_age.or(from_header.max_age),
+                refresh: body.refresh || from_header.refresh,
+                only_if_cached: body.only_if_cached || from_header.only_if_cached,
+            },
+            None => from_header,
+        }
+    }
+
+    pub fn from_cache_control(value: &str) -> Self {
+        let mut directives = CacheDirectives::default();
+        for directive in value.split(',').map(str::trim) {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name.trim(), Some(argument.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => directives.bypass = true,
+                "no-cache" => directives.refresh = true,
+                "only-if-cached" => directives.only_if_cached = true,
+                "max-age" => directives.max_age = argument.and_then(|a| a.parse().ok()),
+                _ => {}
+            }
+        }
+        directives
+    }
+
+    // Without max_age the entry level TTL decides, with it the client decides how old is too old.
+    // Entries past their TTL are only available as long as moka still holds them.
+    pub fn accepts(&self, entry: &CacheEntry, now: Instant) -> bool {
+        match self.max_age {
+            Some(max_age) => now.duration_since(entry.stored_at) <= Duration::from_secs(max_age),
+            None => now < entry.expires_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cache_control_directives() {
+        let directives = CacheDirectives::from_cache_control("no-cache, max-age=30");
+        assert!(directives.refresh);
+        assert_eq!(directives.max_age, Some(30));
+        assert!(!directives.bypass);
+        assert!(!directives.only_if_cached);
+
+        let directives = CacheDirectives::from_cache_control("No-Store,only-if-cached");
+        assert!(directives.bypass);
+        assert!(directives.only_if_cached);
+    }
+
+    #[test]
+    fn tolerates_quotes_spaces_and_unknown_directives() {
+        let directives = CacheDirectives::from_cache_control(" max-age = \"5\" , private");
+        assert_eq!(directives.max_age, Some(5));
+        assert_eq!(
+            CacheDirectives::from_cache_control("max-age=soon").max_age,
+            None
+        );
+        assert_eq!(CacheDirectives::from_cache_control("").max_age, None);
+    }
+
+    #[test]
+    fn body_directives_take_precedence() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CACHE_CONTROL,
+            "max-age=60, no-store".parse().unwrap(),
+        );
+        let body = CacheDirectives {
+            max_age: Some(5),
+            ..CacheDirectives::default()
+        };
+        let directives = CacheDirectives::resolve(&headers, Some(body));
+        assert_eq!(directives.max_age, Some(5));
+        assert!(directives.bypass);
+    }
+}
@@ -3,20 +3,63 @@ use std::collections::HashMap;
 use crate::config::Config;
 
 pub struct QueryMatcher {
-    templates: HashMap<String, super::QueryTemplate>,
+    templates: HashMap<String, Vec<super::QueryTemplate>>, // The same SQL may be a template on several databases
+    default_database: Option<String>,
 }
 
 impl QueryMatcher {
     pub fn new(config: &Config) -> Self {
-        let mut templates = HashMap::new();
+        let mut templates: HashMap<String, Vec<super::QueryTemplate>> = HashMap::new();
         for query in &config.queries {
-            templates.insert(query.sql.clone(), query.clone());
+            templates
+                .entry(query.sql.clone())
+                .or_default()
+                .push(query.clone());
         }
-        QueryMatcher { templates }
+        QueryMatcher {
+            templates,
+            default_database: config.default_database().map(str::to_string),
+        }
+    }
+
+    // Without an explicit database the template on the default database wins
+    pub fn find_template(
+        &self,
+        sql: &str,
+        database: Option<&str>,
+    ) -> Option<&super::QueryTemplate> {
+        let candidates = self.templates.get(sql)?;
+        match database {
+            Some(database) => candidates
+                .iter()
+                .find(|template| self.database_of(template) == Some(database)),
+            None => candidates
+                .iter()
+                .find(|template| self.database_of(template) == self.default_database.as_deref())
+                .or(candidates.first()),
+        }
+    }
+
+    pub fn find_template_by_name(&self, name: &str) -> Option<&super::QueryTemplate> {
+        self.templates
+            .values()
+            .flatten()
+            .find(|template| template.name == name)
+    }
+
+    pub fn templates(&self) -> impl Iterator<Item = &super::QueryTemplate> {
+        self.templates.values().flatten()
+    }
+
+    pub fn database_of<'a>(&'a self, template: &'a super::QueryTemplate) -> Option<&'a str> {
+        template
+            .database
+            .as_deref()
+            .or(self.default_database.as_deref())
     }
 
-    pub fn find_template(&self, sql: &str) -> Option<&super::QueryTemplate> {
-        self.templates.get(sql)
+    pub fn default_database(&self) -> Option<&str> {
+        self.default_database.as_deref()
     }
 
     pub fn template_exists(&self, sql: &str) -> bool {
@@ -1,11 +1,210 @@
-use serde::Deserialize;
+use std::fmt;
+use std::time::Duration;
 
+use serde::de::{self, Unexpected, Visitor};
+use serde::{Deserialize, Deserializer};
+
+use crate::auth::jwt::ClaimBinding;
+use crate::database::statement;
+
+pub mod auto;
+pub mod control;
 pub mod matcher;
+pub mod pagination;
+pub mod snapshot;
 pub mod store;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct QueryTemplate {
     pub name: String,
     pub sql: String,
-    pub ttl: Option<u64>,
+    pub ttl: Option<Ttl>,
+    pub min_ttl: Option<u64>, // Bounds of an adaptive ttl, default to 1 and cache.global_ttl
+    pub max_ttl: Option<u64>,
+    #[serde(default)]
+    pub invalidates: Vec<String>, // Templates whose cached results are dropped after this one runs
+    pub read_only: Option<bool>,  // Detected from the SQL when not set
+    pub database: Option<String>, // Named database from [databases.<name>], the default when not set
+    pub timeout_ms: Option<u64>,  // Overrides server.query_timeout_ms
+    pub max_rows: Option<usize>,  // Overrides limits.max_rows
+    pub max_response_bytes: Option<usize>, // Overrides limits.max_response_bytes
+    pub pagination: Option<PaginationConfig>,
+    #[serde(default)]
+    pub tags: Vec<String>, // Lets API keys be scoped to groups of templates
+    #[serde(default)]
+    pub bind_claims: Vec<ClaimBinding>, // JWT claims bound to parameters on the server
+}
+
+// Seconds, or "adaptive" to follow how often the template's results change
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ttl {
+    Seconds(u64),
+    Adaptive,
+}
+
+impl<'de> Deserialize<'de> for Ttl {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TtlVisitor;
+
+        impl Visitor<'_> for TtlVisitor {
+            type Value = Ttl;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number of seconds or \"adaptive\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, seconds: u64) -> Result<Ttl, E> {
+                Ok(Ttl::Seconds(seconds))
+            }
+
+            fn visit_i64<E: de::Error>(self, seconds: i64) -> Result<Ttl, E> {
+                u64::try_from(seconds)
+                    .map(Ttl::Seconds)
+                    .map_err(|_| E::invalid_value(Unexpected::Signed(seconds), &self))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Ttl, E> {
+                match value {
+                    "adaptive" => Ok(Ttl::Adaptive),
+                    _ => Err(E::invalid_value(Unexpected::Str(value), &self)),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(TtlVisitor)
+    }
+}
+
+// Paginated templates are served page by page, each page is cached on its own
+#[derive(Debug, Deserialize, Clone)]
+pub struct PaginationConfig {
+    pub default_page_size: Option<usize>, // Defaults to 100
+    pub max_page_size: Option<usize>,     // Defaults to 1000
+    pub key: Option<String>, // Unique, ordered column for keyset pagination, offset pagination when not set
+}
+
+impl QueryTemplate {
+    // Read-only templates may be served by a replica on a cache miss
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+            .unwrap_or_else(|| statement::is_read_only(&self.sql))
+    }
+
+    pub fn ttl_bounds(&self, global_ttl: u64) -> (u64, u64) {
+        (
+            self.min_ttl.unwrap_or(1),
+            self.max_ttl.unwrap_or(global_ttl),
+        )
+    }
+
+    pub fn is_adaptive(&self) -> bool {
+        self.ttl == Some(Ttl::Adaptive)
+    }
+
+    // How long the cache keeps the template's results. Adaptive results are kept for twice their
+    // longest TTL, so an expired result is still there to compare the re-fetched one with.
+    pub fn retention(&self, global_ttl: u64) -> u64 {
+        match self.ttl {
+            Some(Ttl::Seconds(ttl)) => ttl,
+            Some(Ttl::Adaptive) => self.ttl_bounds(global_ttl).1 * 2,
+            None => global_ttl,
+        }
+    }
+
+    // TTL for a freshly fetched result. `previous` is the etag and TTL of the entry it replaces:
+    // an adaptive TTL doubles while the result comes back unchanged and halves when it changed,
+    // and starts at min_ttl.
+    pub fn next_ttl(
+        &self,
+        global_ttl: u64,
+        previous: Option<(&str, Duration)>,
+        etag: &str,
+    ) -> Duration {
+        match self.ttl {
+            Some(Ttl::Seconds(ttl)) => return Duration::from_secs(ttl),
+            None => return Duration::from_secs(global_ttl),
+            Some(Ttl::Adaptive) => {}
+        }
+        let (min, max) = self.ttl_bounds(global_ttl);
+        let (min, max) = (Duration::from_secs(min), Duration::from_secs(max));
+        let ttl = match previous {
+            Some((previous_etag, ttl)) if previous_etag == etag => ttl * 2,
+            Some((_, ttl)) => ttl / 2,
+            None => min,
+        };
+        ttl.clamp(min, max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(ttl: &str) -> QueryTemplate {
+        toml::from_str(&format!("name = \"get_user\"\nsql = \"SELECT 1\"\n{}", ttl)).unwrap()
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn fixed_ttls_ignore_previous_results() {
+        let fixed = template("ttl = 30");
+        assert_eq!(
+            fixed.next_ttl(60, Some(("\"a\"", secs(5))), "\"a\""),
+            secs(30)
+        );
+        assert_eq!(template("").next_ttl(60, None, "\"a\""), secs(60));
+    }
+
+    #[test]
+    fn adaptive_ttl_starts_at_min_ttl() {
+        let adaptive = template("ttl = \"adaptive\"\nmin_ttl = 4\nmax_ttl = 64");
+        assert_eq!(adaptive.next_ttl(300, None, "\"a\""), secs(4));
+    }
+
+    #[test]
+    fn adaptive_ttl_doubles_while_unchanged_and_halves_on_change() {
+        let adaptive = template("ttl = \"adaptive\"\nmin_ttl = 4\nmax_ttl = 64");
+        assert_eq!(
+            adaptive.next_ttl(300, Some(("\"a\"", secs(8))), "\"a\""),
+            secs(16)
+        );
+        assert_eq!(
+            adaptive.next_ttl(300, Some(("\"a\"", secs(8))), "\"b\""),
+            secs(4)
+        );
+    }
+
+    #[test]
+    fn adaptive_ttl_stays_within_bounds() {
+        let adaptive = template("ttl = \"adaptive\"\nmin_ttl = 4\nmax_ttl = 64");
+        assert_eq!(
+            adaptive.next_ttl(300, Some(("\"a\"", secs(48))), "\"a\""),
+            secs(64)
+        );
+        assert_eq!(
+            adaptive.next_ttl(300, Some(("\"a\"", secs(5))), "\"b\""),
+            secs(4)
+        );
+        // max_ttl defaults to the global TTL
+        let unbounded = template("ttl = \"adaptive\"");
+        assert_eq!(
+            unbounded.next_ttl(20, Some(("\"a\"", secs(16))), "\"a\""),
+            secs(20)
+        );
+        assert_eq!(unbounded.retention(20), 40);
+    }
+
+    #[test]
+    fn ttl_must_be_seconds_or_adaptive() {
+        let err =
+            toml::from_str::<QueryTemplate>("name = \"a\"\nsql = \"SELECT 1\"\nttl = \"often\"")
+                .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("a number of seconds or \"adaptive\"")
+        );
+    }
 }
@@ -0,0 +1,276 @@
+use base64::{Engine as _, engine::general_purpose};
+use serde::{Deserialize, Serialize};
+
+use super::PaginationConfig;
+use crate::database::value::PostcardValue;
+use crate::error::PledgeError;
+use crate::handlers::query::postcard_to_json;
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const DEFAULT_MAX_PAGE_SIZE: usize = 1_000;
+
+// What the client asked for, `cursor` is the opaque `next_cursor` of the previous page
+#[derive(Debug, Default, Clone)]
+pub struct PageRequest {
+    pub size: Option<usize>,
+    pub cursor: Option<String>,
+}
+
+impl PageRequest {
+    pub fn is_requested(&self) -> bool {
+        self.size.is_some() || self.cursor.is_some()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Cursor {
+    #[serde(rename = "o")]
+    Offset(u64),
+    #[serde(rename = "k")]
+    Key(serde_json::Value), // Key of the last row on the previous page
+}
+
+// A template query rewritten to fetch one page. One row more than the page size is fetched to
+// find out whether there is a next page.
+pub struct Page {
+    pub sql: String,
+    pub params: Vec<serde_json::Value>,
+    size: usize,
+    offset: u64,
+    key: Option<String>,
+}
+
+impl PaginationConfig {
+    // Page size used without page_size, and the largest one a client may ask for
+    pub fn page_sizes(&self) -> (usize, usize) {
+        (
+            self.default_page_size.unwrap_or(DEFAULT_PAGE_SIZE),
+            self.max_page_size.unwrap_or(DEFAULT_MAX_PAGE_SIZE),
+        )
+    }
+}
+
+pub fn paginate(
+    sql: &str,
+    params: &[serde_json::Value],
+    config: &PaginationConfig,
+    request: &PageRequest,
+) -> Result<Page, PledgeError> {
+    let (default_page_size, max_page_size) = config.page_sizes();
+    let size = request.size.unwrap_or(default_page_size);
+    if size == 0 || size > max_page_size {
+        return Err(PledgeError::bad_request(format!(
+            "page_size must be between 1 and {}",
+            max_page_size
+        )));
+    }
+
+    let cursor = match &request.cursor {
+        Some(cursor) => Some(decode_cursor(cursor)?),
+        None => None,
+    };
+    let mut params = params.to_vec();
+    let sql = sql.trim().trim_end_matches(';');
+    params.push(serde_json::json!(size + 1));
+    let limit = params.len();
+
+    match (&config.key, cursor) {
+        (Some(key), cursor) => {
+            let column = format!("pledge_page.{}", quote_ident(key));
+            let filter = match cursor {
+                Some(Cursor::Key(last)) => {
+                    params.push(last);
+                    format!(" WHERE {} > ${}", column, params.len())
+                }
+                None => String::new(),
+                Some(Cursor::Offset(_)) => return Err(invalid_cursor()),
+            };
+            Ok(Page {
+                sql: format!(
+                    "SELECT * FROM ({}) AS pledge_page{} ORDER BY {} LIMIT ${}",
+                    sql, filter, column, limit
+                ),
+                params,
+                size,
+                offset: 0,
+                key: Some(key.clone()),
+            })
+        }
+        (None, cursor) => {
+            let offset = match cursor {
+                Some(Cursor::Offset(offset)) => offset,
+                None => 0,
+                Some(Cursor::Key(_)) => return Err(invalid_cursor()),
+            };
+            params.push(serde_json::json!(offset));
+            Ok(Page {
+                sql: format!(
+                    "SELECT * FROM ({}) AS pledge_page LIMIT ${} OFFSET ${}",
+                    sql,
+                    limit,
+                    params.len()
+                ),
+                params,
+                size,
+                offset,
+                key: None,
+            })
+        }
+    }
+}
+
+impl Page {
+    // Drops the look-ahead row and returns the cursor of the next page, if there is one. Fails when
+    // the keyset column isn't in the result, the next page couldn't be found without it.
+    pub fn finish(&self, rows: &mut Vec<PostcardValue>) -> Result<Option<String>, PledgeError> {
+        if rows.len() <= self.size {
+            return Ok(None);
+        }
+        rows.truncate(self.size);
+
+        let cursor = match &self.key {
+            Some(key) => {
+                let value = match rows.last() {
+                    Some(PostcardValue::Object(fields)) => fields
+                        .iter()
+                        .find(|(name, _)| name == key)
+                        .map(|(_, value)| postcard_to_json(value)),
+                    _ => None,
+                };
+                match value {
+                    Some(value) => Cursor::Key(value),
+                    None => {
+                        return Err(PledgeError::internal(format!(
+                            "Pagination key '{}' is not a column of the query's result",
+                            key
+                        )));
+                    }
+                }
+            }
+            None => Cursor::Offset(self.offset + self.size as u64),
+        };
+        let json = serde_json::to_vec(&cursor).map_err(|e| PledgeError::internal(e.to_string()))?;
+        Ok(Some(general_purpose::URL_SAFE_NO_PAD.encode(json)))
+    }
+}
+
+fn decode_cursor(cursor: &str) -> Result<Cursor, PledgeError> {
+    let json = general_purpose::URL_SAFE_NO_PAD
+        .decode(cursor)
+        .map_err(|_| invalid_cursor())?;
+    serde_json::from_slice(&json).map_err(|_| invalid_cursor())
+}
+
+fn invalid_cursor() -> PledgeError {
+    PledgeError::bad_request("Invalid pagination cursor")
+}
+
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn config(toml: &str) -> PaginationConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn request(size: Option<usize>, cursor: Option<String>) -> PageRequest {
+        PageRequest { size, cursor }
+    }
+
+    fn rows(ids: impl IntoIterator<Item = i32>) -> Vec<PostcardValue> {
+        ids.into_iter()
+            .map(|id| PostcardValue::Object(vec![("id".to_string(), PostcardValue::Integer32(id))]))
+            .collect()
+    }
+
+    #[test]
+    fn offset_pages_follow_each_other() {
+        let config = config("default_page_size = 2");
+        let sql = "SELECT * FROM posts WHERE user_id = $1;";
+        let page = paginate(sql, &[json!(7)], &config, &request(None, None)).unwrap();
+        assert_eq!(
+            page.sql,
+            "SELECT * FROM (SELECT * FROM posts WHERE user_id = $1) AS pledge_page LIMIT $2 OFFSET $3"
+        );
+        assert_eq!(page.params, vec![json!(7), json!(3), json!(0)]);
+
+        let mut fetched = rows([1, 2, 3]);
+        let cursor = page.finish(&mut fetched).unwrap();
+        assert_eq!(fetched.len(), 2);
+
+        let next = paginate(sql, &[json!(7)], &config, &request(None, cursor)).unwrap();
+        assert_eq!(next.params, vec![json!(7), json!(3), json!(2)]);
+    }
+
+    #[test]
+    fn keyset_pages_continue_after_the_last_key() {
+        let config = config("key = \"id\"");
+        let sql = "SELECT * FROM posts";
+        let page = paginate(sql, &[], &config, &request(Some(2), None)).unwrap();
+        assert_eq!(
+            page.sql,
+            "SELECT * FROM (SELECT * FROM posts) AS pledge_page ORDER BY pledge_page.\"id\" LIMIT $1"
+        );
+
+        let mut fetched = rows([4, 9, 12]);
+        let cursor = page.finish(&mut fetched).unwrap();
+        assert_eq!(fetched.len(), 2);
+
+        let next = paginate(sql, &[], &config, &request(Some(2), cursor)).unwrap();
+        assert_eq!(
+            next.sql,
+            "SELECT * FROM (SELECT * FROM posts) AS pledge_page WHERE pledge_page.\"id\" > $2 ORDER BY pledge_page.\"id\" LIMIT $1"
+        );
+        assert_eq!(next.params, vec![json!(3), json!(9)]);
+    }
+
+    #[test]
+    fn last_page_has_no_cursor() {
+        let page = paginate("SELECT 1", &[], &config(""), &request(Some(2), None)).unwrap();
+        let mut fetched = rows([1, 2]);
+        assert_eq!(page.finish(&mut fetched).unwrap(), None);
+        assert_eq!(fetched.len(), 2);
+    }
+
+    #[test]
+    fn keyset_pages_need_the_key_column() {
+        let page = paginate(
+            "SELECT name FROM users",
+            &[],
+            &config("key = \"id\""),
+            &request(Some(1), None),
+        )
+        .unwrap();
+        let mut fetched = vec![PostcardValue::Object(vec![]), PostcardValue::Object(vec![])];
+        assert!(page.finish(&mut fetched).is_err());
+    }
+
+    #[test]
+    fn page_size_must_be_within_bounds() {
+        let config = config("max_page_size = 50");
+        assert!(paginate("SELECT 1", &[], &config, &request(Some(0), None)).is_err());
+        assert!(paginate("SELECT 1", &[], &config, &request(Some(51), None)).is_err());
+        assert!(paginate("SELECT 1", &[], &config, &request(Some(50), None)).is_ok());
+    }
+
+    #[test]
+    fn rejects_foreign_cursors() {
+        let offset = paginate("SELECT 1", &[], &config(""), &request(Some(1), None)).unwrap();
+        let offset_cursor = offset.finish(&mut rows([1, 2])).unwrap();
+        let keyset = config("key = \"id\"");
+        let err = paginate("SELECT 1", &[], &keyset, &request(None, offset_cursor));
+        assert_eq!(
+            err.err().map(|e| e.body.message),
+            Some("Invalid pagination cursor".to_string())
+        );
+
+        let garbage = Some("not a cursor".to_string());
+        assert!(paginate("SELECT 1", &[], &keyset, &request(None, garbage)).is_err());
+    }
+}
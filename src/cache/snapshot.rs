@@ -0,0 +1,74 @@
+use std::fs;
+use std::time::{Duration, Instant};
+
+use moka::sync::Cache;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::matcher::QueryMatcher;
+use crate::cache::store::CacheEntry;
+
+// A cache entry as written to disk, Instants only make sense within one process
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    key: String,
+    data: Vec<u8>,
+    etag: String,
+    template: String,
+    age_ms: u64,
+    remaining_ms: u64, // Time left before the entry expires
+}
+
+// Writes every unexpired entry to `path`, through a temporary file so a crash never leaves half a snapshot
+pub fn save(
+    cache: &Cache<String, CacheEntry>,
+    path: &str,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let now = Instant::now();
+    let entries: Vec<SnapshotEntry> = cache
+        .iter()
+        .filter(|(_, entry)| entry.expires_at > now)
+        .map(|(key, entry)| SnapshotEntry {
+            key: key.as_ref().clone(),
+            age_ms: now.duration_since(entry.stored_at).as_millis() as u64,
+            remaining_ms: entry.expires_at.duration_since(now).as_millis() as u64,
+            data: entry.data,
+            etag: entry.etag,
+            template: entry.template,
+        })
+        .collect();
+
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, postcard::to_allocvec(&entries)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(entries.len())
+}
+
+// Restores a snapshot written by `save`. Entries of templates that no longer exist are skipped,
+// changed SQL gets new cache keys so stale entries are never served.
+pub fn load(
+    cache: &Cache<String, CacheEntry>,
+    matcher: &QueryMatcher,
+    path: &str,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let entries: Vec<SnapshotEntry> = postcard::from_bytes(&fs::read(path)?)?;
+    let now = Instant::now();
+    let mut loaded = 0;
+    for entry in entries {
+        if matcher.find_template_by_name(&entry.template).is_none() {
+            continue;
+        }
+        let age = Duration::from_millis(entry.age_ms);
+        cache.insert(
+            entry.key,
+            CacheEntry {
+                data: entry.data,
+                stored_at: now.checked_sub(age).unwrap_or(now),
+                expires_at: now + Duration::from_millis(entry.remaining_ms),
+                etag: entry.etag,
+                template: entry.template,
+            },
+        );
+        loaded += 1;
+    }
+    Ok(loaded)
+}
@@ -1,8 +1,78 @@
 use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::Instant;
 
-pub fn cache_key(query: &str, params: &[serde_json::Value]) -> String {
+use moka::sync::Cache;
+use tracing::warn;
+
+use crate::database::session::Session;
+
+#[derive(Clone)]
+pub struct CacheEntry {
+    pub data: Vec<u8>, // Serialized (postcard) response
+    pub stored_at: Instant,
+    pub expires_at: Instant,
+    pub etag: String,
+    pub template: String, // Name of the QueryTemplate that produced the entry
+}
+
+// Namespaced by database so the same SQL on two databases never shares an entry
+pub fn cache_key(
+    database: &str,
+    session: Option<&Session>,
+    query: &str,
+    params: &[serde_json::Value],
+) -> String {
     let mut hasher = DefaultHasher::new();
+    database.hash(&mut hasher);
+    // Results under row-level security differ per role and claims
+    session.hash(&mut hasher);
     query.hash(&mut hasher);
     params.hash(&mut hasher);
     hasher.finish().to_string()
 }
+
+// Strong ETag derived from the serialized result, so identical rows always produce the same tag
+pub fn content_etag(data: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+// Checks an If-None-Match header value (a list of tags or "*") against an ETag
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+// Drops every cached result produced by one of the named templates
+pub fn invalidate_templates(cache: &Cache<String, CacheEntry>, templates: &[String]) {
+    let templates = templates.to_vec();
+    match cache.invalidate_entries_if(move |_key, entry| templates.contains(&entry.template)) {
+        Ok(_) => {}
+        Err(err) => warn!("Failed to invalidate cache entries: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn etag_matches_any_listed_tag() {
+        let etag = "\"0123456789abcdef\"";
+        assert!(etag_matches(etag, etag));
+        assert!(etag_matches("\"other\", \"0123456789abcdef\"", etag));
+        assert!(etag_matches("*", etag));
+        assert!(!etag_matches("\"other\"", etag));
+        assert!(!etag_matches("0123456789abcdef", etag));
+    }
+
+    #[test]
+    fn weak_tags_match_weakly() {
+        assert!(etag_matches(
+            "W/\"0123456789abcdef\"",
+            "\"0123456789abcdef\""
+        ));
+    }
+}
@@ -1,25 +1,89 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
 
+use crate::auth::Scopes;
+use crate::auth::jwt::JwtConfig;
 use crate::cache::QueryTemplate;
+use crate::cache::auto::AUTO_TEMPLATE;
+use crate::database::statement;
+use crate::database::upstream::ReplicaStrategy;
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
-    pub database: DatabaseConfig,
+    pub database: Option<DatabaseConfig>, // Registered as the database named "default"
+    #[serde(default)]
+    pub databases: HashMap<String, DatabaseConfig>,
     pub queries: Vec<QueryTemplate>,
     pub cache: CacheConfig,
     pub server: ServerConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    pub auth: Option<AuthConfig>,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    pub telemetry: Option<TelemetryConfig>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct DatabaseConfig {
     pub url: String,
+    pub min_connections: Option<u32>,
+    pub max_connections: Option<u32>, // Defaults to 5
+    pub acquire_timeout_secs: Option<u64>,
+    pub idle_timeout_secs: Option<u64>,
+    pub max_lifetime_secs: Option<u64>,
+    pub statement_timeout_ms: Option<u64>,
+    pub application_name: Option<String>,
+    pub ssl_mode: Option<String>, // disable, allow, prefer, require, verify-ca or verify-full
+    pub ssl_root_cert: Option<String>,
+    pub test_before_acquire: Option<bool>,
+    #[serde(default)]
+    pub replicas: Vec<ReplicaConfig>,
+    pub replica_strategy: Option<ReplicaStrategy>, // round_robin (default) or least_connections
+    pub max_replica_lag_secs: Option<f64>,
+    pub replica_check_interval_secs: Option<u64>, // Defaults to 5
+}
+
+// Replicas share the pool and session settings of their primary
+#[derive(Debug, Deserialize)]
+pub struct ReplicaConfig {
+    pub url: String,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CacheConfig {
     pub global_ttl: u64,
     pub max_size_mib: Option<u64>,
+    pub max_entry_bytes: Option<usize>, // Larger results are served but never cached
+    pub snapshot_path: Option<String>,  // Cache written here on shutdown and loaded on startup
+    pub auto_cache: Option<AutoCacheConfig>,
+}
+
+// Caches read-only SQL that matches no template once it is seen often and is slow
+#[derive(Debug, Deserialize, Clone)]
+pub struct AutoCacheConfig {
+    pub min_calls: Option<u64>, // Calls before a statement is cached, defaults to 10
+    pub min_duration_ms: Option<u64>, // Average time in the database, defaults to 100
+    pub ttl: Option<u64>,       // Defaults to 5
+    pub max_share: Option<f64>, // Share of the cache size auto entries may fill, defaults to 0.1
+}
+
+// Defaults for templates without their own max_rows / max_response_bytes, and for ad-hoc SQL
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct LimitsConfig {
+    pub max_rows: Option<usize>,
+    pub max_response_bytes: Option<usize>,
+    #[serde(default)]
+    pub on_limit: LimitAction,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitAction {
+    #[default]
+    Error, // 413 Payload Too Large
+    Truncate, // Return the rows up to the limit with `truncated: true`
 }
 
 #[derive(Debug, Deserialize)]
@@ -28,10 +92,208 @@ pub struct ServerConfig {
     pub https_port: Option<u16>,
     pub tls_cert_path: Option<String>,
     pub tls_key_path: Option<String>,
+    pub tls_client_ca_path: Option<String>, // Require client certificates signed by this CA on HTTPS
+    pub tls_reload_interval_secs: Option<u64>, // How often the TLS files are checked for changes, defaults to 10
+    #[serde(default)]
+    pub http_mode: HttpMode,
+    pub query_timeout_ms: Option<u64>, // Default for templates without timeout_ms and ad-hoc SQL
+    pub shutdown_timeout_secs: Option<u64>, // How long in-flight requests may drain on shutdown, defaults to 30
+    pub ready_when_degraded: Option<bool>, // /health/ready answers 200 while only replicas are degraded, defaults to false
+}
+
+// RUST_LOG overrides level and filter
+#[derive(Debug, Deserialize, Default)]
+pub struct LoggingConfig {
+    pub level: Option<String>,  // Defaults to "info"
+    pub filter: Option<String>, // Per-module directives, e.g. "pledge=debug,sqlx=warn"
+    #[serde(default)]
+    pub format: LogFormat,
+    #[serde(default)]
+    pub log_params: bool, // Log query parameter values, they may hold personal data
+    pub slow_query_ms: Option<u64>, // Statements slower than this are logged under the "slow_query" target
+}
+
+// OpenTelemetry trace export over OTLP/HTTP
+#[derive(Debug, Deserialize)]
+pub struct TelemetryConfig {
+    pub endpoint: String,             // e.g. "http://localhost:4318/v1/traces"
+    pub service_name: Option<String>, // Defaults to "pledge"
+    pub sample_ratio: Option<f64>,    // Share of new traces to sample, defaults to all
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json, // One JSON object per line
+}
+
+// What the plain HTTP port serves once HTTPS is configured
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpMode {
+    #[default]
+    Full,
+    Redirect,   // 308 to the same path on https_port
+    HealthOnly, // Only /health, e.g. for load balancer checks
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthConfig {
+    pub enabled: Option<bool>, // Defaults to true once [auth] is present
+    #[serde(default)]
+    pub api_keys: Vec<ApiKeyConfig>,
+    pub keys_file: Option<String>, // TOML file with more [[api_keys]], kept out of pledge.toml
+    pub jwt: Option<JwtConfig>,
+    #[serde(default)]
+    pub client_certs: Vec<ClientCertConfig>,
+    pub rls: Option<bool>, // Run queries as the identity's Postgres role, with its JWT claims set
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ApiKeyConfig {
+    pub name: String,
+    pub key_sha256: String, // Hex SHA-256 of the secret, e.g. `printf %s "$KEY" | sha256sum`
+    pub role: Option<String>, // Postgres role used with auth.rls
+    #[serde(flatten)]
+    pub scopes: Scopes,
+}
+
+// Maps a verified client certificate to an identity, see server.tls_client_ca_path
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClientCertConfig {
+    pub name: String,
+    pub subject: String, // Matched against the certificate's CN and DNS, URI and email SANs
+    pub role: Option<String>,
+    #[serde(flatten)]
+    pub scopes: Scopes,
+}
+
+pub const DEFAULT_DATABASE: &str = "default";
+
+impl Config {
+    pub fn database_configs(&self) -> Vec<(&str, &DatabaseConfig)> {
+        let mut configs: Vec<(&str, &DatabaseConfig)> = self
+            .databases
+            .iter()
+            .map(|(name, config)| (name.as_str(), config))
+            .collect();
+        if let Some(database) = &self.database {
+            configs.push((DEFAULT_DATABASE, database));
+        }
+        configs
+    }
+
+    // Used by templates and requests that don't name a database. With only named databases
+    // there is no default unless exactly one is configured.
+    pub fn default_database(&self) -> Option<&str> {
+        if self.database.is_some() {
+            return Some(DEFAULT_DATABASE);
+        }
+        match self.databases.len() {
+            1 => self.databases.keys().next().map(String::as_str),
+            _ => None,
+        }
+    }
 }
 
 pub fn load_config() -> Result<Config, Box<dyn std::error::Error>> {
     let contents = fs::read_to_string("pledge.toml")?;
     let config: Config = toml::from_str(&contents)?;
+
+    let names: Vec<&str> = config.database_configs().iter().map(|(n, _)| *n).collect();
+    if names.is_empty() {
+        return Err(
+            "No database configured, add a [database] or [databases.<name>] section".into(),
+        );
+    }
+    if config.database.is_some() && config.databases.contains_key(DEFAULT_DATABASE) {
+        return Err("[databases.default] clashes with [database], use one or the other".into());
+    }
+    let https = config
+        .server
+        .https_port
+        .is_some_and(|port| port != config.server.port)
+        && config.server.tls_cert_path.is_some()
+        && config.server.tls_key_path.is_some();
+    if config.server.http_mode != HttpMode::Full && !https {
+        return Err(
+            "server.http_mode needs HTTPS on its own https_port, with tls_cert_path and tls_key_path".into(),
+        );
+    }
+    // Invalidation, stats, metrics and snapshots all refer to templates by name
+    let mut template_names = HashSet::new();
+    // Raw SQL is matched to a template by its text, which has to be unambiguous per database
+    let mut template_sql = HashSet::new();
+    for query in &config.queries {
+        if !template_names.insert(query.name.as_str()) {
+            return Err(format!("Query name '{}' is used more than once", query.name).into());
+        }
+        if query.name == AUTO_TEMPLATE {
+            return Err(format!(
+                "Query name '{}' is reserved for auto_cache entries",
+                AUTO_TEMPLATE
+            )
+            .into());
+        }
+        // A binding the SQL doesn't use would leave the claim unchecked
+        for binding in &query.bind_claims {
+            if binding.param == 0 {
+                return Err(format!(
+                    "Query '{}' binds claim '{}' to param 0, params start at 1",
+                    query.name, binding.claim
+                )
+                .into());
+            }
+            if !statement::references_param(&query.sql, binding.param) {
+                return Err(format!(
+                    "Query '{}' binds claim '{}' to ${}, which its SQL doesn't use",
+                    query.name, binding.claim, binding.param
+                )
+                .into());
+            }
+        }
+        if let Some(pagination) = &query.pagination {
+            let (default_page_size, max_page_size) = pagination.page_sizes();
+            if default_page_size == 0 || default_page_size > max_page_size {
+                return Err(format!(
+                    "Query '{}' needs 0 < default_page_size <= max_page_size, they default to 100 and 1000",
+                    query.name
+                )
+                .into());
+            }
+        }
+        let (min_ttl, max_ttl) = query.ttl_bounds(config.cache.global_ttl);
+        if query.is_adaptive() && (min_ttl == 0 || min_ttl > max_ttl) {
+            return Err(format!(
+                "Query '{}' needs 0 < min_ttl <= max_ttl, max_ttl defaults to cache.global_ttl",
+                query.name
+            )
+            .into());
+        }
+        let database = match query.database.as_deref().or(config.default_database()) {
+            Some(name) if names.contains(&name) => name,
+            Some(name) => {
+                return Err(
+                    format!("Query '{}' uses unknown database '{}'", query.name, name).into(),
+                );
+            }
+            None => {
+                return Err(format!(
+                    "Query '{}' must set `database`, there is no default database",
+                    query.name
+                )
+                .into());
+            }
+        };
+        if !template_sql.insert((database, query.sql.as_str())) {
+            return Err(format!(
+                "Query '{}' has the same SQL as another query on database '{}'",
+                query.name, database
+            )
+            .into());
+        }
+    }
     Ok(config)
 }
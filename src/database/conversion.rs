@@ -11,7 +11,7 @@ pub fn convert_row_val_to_postcard(
     index: usize,
 ) -> PostcardValue {
     // Types are taken from here: https://docs.rs/sqlx/latest/sqlx/postgres/types/index.html
-    return match column.type_info().name() {
+    match column.type_info().name() {
         "BOOL" => {
             let val: bool = row_val.get(index);
             PostcardValue::Bool(val)
@@ -75,5 +75,5 @@ pub fn convert_row_val_to_postcard(
             PostcardValue::String(val.to_string())
         }
         _ => PostcardValue::Null,
-    };
+    }
 }
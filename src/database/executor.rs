@@ -0,0 +1,243 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::http::StatusCode;
+use futures::TryStreamExt;
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::PgConnectOptions;
+use sqlx::{Column, Connection, PgConnection, PgExecutor, PgPool, Postgres, Row};
+use tracing::{Instrument, Span, field, info, info_span, instrument, warn};
+
+use crate::database::session::Session;
+use crate::database::value::PostcardValue;
+use crate::database::{conversion, pool};
+use crate::error::PledgeError;
+use crate::metrics::METRICS;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResultLimits {
+    pub max_rows: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub truncate: bool, // Cut the result off instead of failing with a 413
+}
+
+pub struct FetchedRows {
+    pub rows: Vec<PostcardValue>,
+    pub truncated: bool,
+    pub elapsed: Duration, // Time from sending the statement to the last row
+}
+
+// Runs a statement on a pool, a pinned connection or an open transaction. Rows are streamed so a
+// result over the limits is stopped early instead of being loaded into memory first.
+#[instrument(name = "pg_execute", skip_all, fields(rows = field::Empty))]
+pub async fn execute_query<'e, E>(
+    executor: E,
+    sql: &str,
+    params: &[serde_json::Value],
+    limits: ResultLimits,
+) -> Result<FetchedRows, PledgeError>
+where
+    E: PgExecutor<'e>,
+{
+    let mut query = sqlx::query(sql);
+
+    for param in params {
+        // Bind each parameter
+        if let Some(num) = param.as_i64() {
+            query = query.bind(num);
+        } else if let Some(text) = param.as_str() {
+            query = query.bind(text);
+        } else if let Some(bool) = param.as_bool() {
+            query = query.bind(bool);
+        } else if let Some(array) = param.as_array() {
+            query = query.bind(array);
+        } else if let Some(num) = param.as_f64() {
+            query = query.bind(num);
+        } else {
+            return Err(PledgeError::bad_request("Unsupported parameter type"));
+        }
+    }
+
+    let started = Instant::now();
+    let mut stream = query.fetch(executor);
+    let mut rows: Vec<PostcardValue> = Vec::new();
+    let mut bytes = 0;
+
+    while let Some(row) = stream.try_next().await? {
+        let mut fields: Vec<(String, PostcardValue)> = Vec::new();
+        for (i, column) in row.columns().iter().enumerate() {
+            let value = conversion::convert_row_val_to_postcard(&row, column, i);
+            fields.push((column.name().to_string(), value));
+        }
+        let row = PostcardValue::Object(fields);
+        bytes += row.estimated_size();
+
+        let over_rows = limits.max_rows.is_some_and(|max| rows.len() >= max);
+        let over_bytes = limits.max_bytes.is_some_and(|max| bytes > max);
+        if over_rows || over_bytes {
+            if limits.truncate {
+                return Ok(FetchedRows {
+                    rows,
+                    truncated: true,
+                    elapsed: started.elapsed(),
+                });
+            }
+            return Err(PledgeError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "result_too_large",
+                match over_rows {
+                    true => format!("Result exceeds {} rows", limits.max_rows.unwrap()),
+                    false => format!("Result exceeds {} bytes", limits.max_bytes.unwrap()),
+                },
+            ));
+        }
+        rows.push(row);
+    }
+
+    Span::current().record("rows", rows.len());
+    Ok(FetchedRows {
+        rows,
+        truncated: false,
+        elapsed: started.elapsed(),
+    })
+}
+
+// Must run before the first query of the transaction
+pub async fn set_read_only(conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+    sqlx::query("SET TRANSACTION READ ONLY")
+        .execute(conn)
+        .await
+        .map(|_| ())
+}
+
+// Runs a statement on a connection of its own so that it can be cancelled. The backend is sent a
+// cancel request when the timeout fires or when the future is dropped because the client went away.
+// With a session or `read_only` the statement runs in a short transaction that carries the session's
+// settings and is declared READ ONLY, so Postgres rejects any write it attempts.
+pub async fn execute_cancellable(
+    pool: &PgPool,
+    sql: &str,
+    params: &[serde_json::Value],
+    timeout: Option<Duration>,
+    limits: ResultLimits,
+    session: Option<&Session>,
+    read_only: bool,
+) -> Result<FetchedRows, PledgeError> {
+    let mut pinned = CancelOnDrop::acquire(pool).await?;
+    let conn = pinned.conn();
+    let query = async {
+        if session.is_none() && !read_only {
+            return execute_query(conn, sql, params, limits).await;
+        }
+        let mut tx = conn.begin().await?;
+        if read_only {
+            set_read_only(&mut tx).await?;
+        }
+        if let Some(session) = session {
+            session.apply(&mut tx).await?;
+        }
+        let fetched = execute_query(&mut *tx, sql, params, limits).await?;
+        // A truncated result is cancelled below, the same as without a transaction
+        if !fetched.truncated {
+            tx.commit().await?;
+        }
+        Ok(fetched)
+    };
+    let result = with_timeout(timeout, query).await;
+
+    // Finished, hand the connection back to the pool. A statement that timed out or whose result
+    // was stopped early leaves the guard armed, so it is cancelled rather than drained.
+    match &result {
+        Ok(fetched) if fetched.truncated => {}
+        Err(err)
+            if err.status == StatusCode::PAYLOAD_TOO_LARGE
+                || err.status == StatusCode::GATEWAY_TIMEOUT => {}
+        _ => pinned.release(),
+    }
+    result
+}
+
+// Fails with a 504 once `timeout` passed. The statement keeps running on the backend until the
+// connection's CancelOnDrop guard is dropped.
+pub async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T, PledgeError>>,
+) -> Result<T, PledgeError> {
+    let Some(timeout) = timeout else {
+        return future.await;
+    };
+    match tokio::time::timeout(timeout, future).await {
+        Ok(result) => result,
+        Err(_) => Err(PledgeError::new(
+            StatusCode::GATEWAY_TIMEOUT,
+            "query_timeout",
+            format!("Query did not finish within {}ms", timeout.as_millis()),
+        )),
+    }
+}
+
+// A pooled connection whose running statement is cancelled on the backend when the guard is
+// dropped before `release`, e.g. on a timeout or because the client went away
+pub struct CancelOnDrop {
+    conn: Option<PoolConnection<Postgres>>,
+    options: Arc<PgConnectOptions>,
+    pid: i32,
+}
+
+impl CancelOnDrop {
+    pub async fn acquire(pool: &PgPool) -> Result<Self, PledgeError> {
+        let mut conn = {
+            let _waiting = METRICS.waiting_for(pool);
+            pool.acquire()
+                .instrument(info_span!("pool_acquire", pool = %pool::label(pool)))
+                .await?
+        };
+        let pid: i32 = sqlx::query_scalar("SELECT pg_backend_pid()")
+            .fetch_one(&mut *conn)
+            .await?;
+        Ok(CancelOnDrop {
+            conn: Some(conn),
+            options: pool.connect_options(),
+            pid,
+        })
+    }
+
+    pub fn conn(&mut self) -> &mut PgConnection {
+        self.conn.as_mut().unwrap()
+    }
+
+    // Hands the connection back to the pool without cancelling anything
+    pub fn release(mut self) {
+        self.conn.take();
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        let Some(conn) = self.conn.take() else {
+            return;
+        };
+        let pid = self.pid;
+        let options = Arc::clone(&self.options);
+        // The connection is held until the cancel went through so it can't be handed to another
+        // request whose query would be cancelled instead, and then closed rather than reused
+        tokio::spawn(async move {
+            if let Err(err) = cancel_backend(&options, pid).await {
+                warn!("Failed to cancel query on backend {}: {}", pid, err);
+            } else {
+                info!("Cancelled query on backend {}", pid);
+            }
+            let _ = conn.close().await;
+        });
+    }
+}
+
+// Uses a fresh connection so the cancel doesn't queue behind an exhausted pool
+async fn cancel_backend(options: &PgConnectOptions, pid: i32) -> Result<(), sqlx::Error> {
+    let mut conn = PgConnection::connect_with(options).await?;
+    sqlx::query("SELECT pg_cancel_backend($1)")
+        .bind(pid)
+        .execute(&mut conn)
+        .await?;
+    conn.close().await
+}
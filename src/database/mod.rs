@@ -1,2 +1,8 @@
 pub mod conversion;
+pub mod executor;
+pub mod pool;
+pub mod registry;
+pub mod session;
+pub mod statement;
+pub mod upstream;
 pub mod value;
@@ -0,0 +1,81 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use sqlx::PgPool;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions, PgSslMode};
+
+use crate::config::DatabaseConfig;
+
+pub async fn connect(config: &DatabaseConfig, url: &str) -> Result<PgPool, sqlx::Error> {
+    let (pool_options, connect_options) = options(config, url)?;
+    pool_options.connect_with(connect_options).await
+}
+
+// host:port/database of a pool, safe to log unlike its URL
+pub fn label(pool: &PgPool) -> String {
+    let options = pool.connect_options();
+    format!(
+        "{}:{}/{}",
+        options.get_host(),
+        options.get_port(),
+        options.get_database().unwrap_or_default()
+    )
+}
+
+// Doesn't open a connection until the pool is first used, so an unreachable server doesn't block startup
+pub fn connect_lazy(config: &DatabaseConfig, url: &str) -> Result<PgPool, sqlx::Error> {
+    let (pool_options, connect_options) = options(config, url)?;
+    Ok(pool_options.connect_lazy_with(connect_options))
+}
+
+fn options(
+    config: &DatabaseConfig,
+    url: &str,
+) -> Result<(PgPoolOptions, PgConnectOptions), sqlx::Error> {
+    let mut connect_options = PgConnectOptions::from_str(url)?;
+    if let Some(ssl_mode) = &config.ssl_mode {
+        connect_options = connect_options.ssl_mode(PgSslMode::from_str(ssl_mode)?);
+    }
+    if let Some(root_cert) = &config.ssl_root_cert {
+        connect_options = connect_options.ssl_root_cert(root_cert);
+    }
+
+    let mut pool_options = PgPoolOptions::new()
+        .min_connections(config.min_connections.unwrap_or(0))
+        .max_connections(config.max_connections.unwrap_or(5));
+    if let Some(secs) = config.acquire_timeout_secs {
+        pool_options = pool_options.acquire_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = config.idle_timeout_secs {
+        pool_options = pool_options.idle_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = config.max_lifetime_secs {
+        pool_options = pool_options.max_lifetime(Duration::from_secs(secs));
+    }
+    if let Some(test) = config.test_before_acquire {
+        pool_options = pool_options.test_before_acquire(test);
+    }
+
+    // Session settings, applied to every new connection before it joins the pool
+    let statement_timeout_ms = config.statement_timeout_ms;
+    let application_name = config.application_name.clone();
+    pool_options = pool_options.after_connect(move |conn, _meta| {
+        let application_name = application_name.clone();
+        Box::pin(async move {
+            if let Some(ms) = statement_timeout_ms {
+                sqlx::query(&format!("SET statement_timeout = {}", ms))
+                    .execute(&mut *conn)
+                    .await?;
+            }
+            if let Some(name) = application_name {
+                sqlx::query("SELECT set_config('application_name', $1, false)")
+                    .bind(name)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+            Ok(())
+        })
+    });
+
+    Ok((pool_options, connect_options))
+}
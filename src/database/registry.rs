@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use futures::future::join_all;
+use tracing::info;
+
+use crate::config::Config;
+use crate::database::upstream::Upstream;
+use crate::error::PledgeError;
+
+// All upstream databases Pledge fronts, by name
+pub struct Databases {
+    upstreams: HashMap<String, Arc<Upstream>>,
+    default: Option<String>,
+}
+
+impl Databases {
+    pub async fn connect(config: &Config) -> Result<Self, sqlx::Error> {
+        let mut upstreams = HashMap::new();
+        for (name, database_config) in config.database_configs() {
+            let upstream = Arc::new(Upstream::connect(name, database_config).await?);
+            upstream.spawn_health_checks(Duration::from_secs(
+                database_config.replica_check_interval_secs.unwrap_or(5),
+            ));
+            info!("Connected to database '{}'", name);
+            upstreams.insert(name.to_string(), upstream);
+        }
+        Ok(Databases {
+            upstreams,
+            default: config.default_database().map(str::to_string),
+        })
+    }
+
+    pub fn get(&self, name: Option<&str>) -> Result<&Arc<Upstream>, PledgeError> {
+        let name = match name.or(self.default.as_deref()) {
+            Some(name) => name,
+            None => {
+                return Err(PledgeError::bad_request(
+                    "No database selected and no default database configured",
+                ));
+            }
+        };
+        self.upstreams.get(name).ok_or_else(|| {
+            PledgeError::new(
+                StatusCode::NOT_FOUND,
+                "unknown_database",
+                format!("No database named '{}'", name),
+            )
+        })
+    }
+
+    // Waits for checked out connections to be returned, then closes every pool
+    pub async fn close(&self) {
+        join_all(self.upstreams.values().map(|upstream| upstream.close())).await;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Upstream>> {
+        self.upstreams.values()
+    }
+}
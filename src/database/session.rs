@@ -0,0 +1,28 @@
+use sqlx::PgConnection;
+
+// Per-request settings for row-level security, PostgREST style. They are applied with `is_local`
+// inside the statement's transaction, so they never outlive it on the pooled connection.
+#[derive(Debug, Clone, Hash, PartialEq)]
+pub struct Session {
+    pub role: Option<String>,   // SET LOCAL ROLE
+    pub claims: Option<String>, // JSON for current_setting('request.jwt.claims')
+}
+
+impl Session {
+    pub async fn apply(&self, conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+        // set_config('role', ...) is SET LOCAL ROLE with the role as a bind parameter
+        if let Some(role) = &self.role {
+            sqlx::query("SELECT set_config('role', $1, true)")
+                .bind(role)
+                .execute(&mut *conn)
+                .await?;
+        }
+        if let Some(claims) = &self.claims {
+            sqlx::query("SELECT set_config('request.jwt.claims', $1, true)")
+                .bind(claims)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+// Conservative check for statements that can safely run on a read replica. Anything unrecognised,
+// including keywords inside string literals, counts as a write and goes to the primary.
+pub fn is_read_only(sql: &str) -> bool {
+    let lowered = sql.to_ascii_lowercase();
+    let mut words = lowered
+        .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty());
+
+    match words.next() {
+        Some("select" | "with" | "values" | "table" | "show") => {}
+        _ => return false,
+    }
+
+    !words.any(|word| {
+        matches!(
+            word,
+            "insert"
+                | "update" // Also catches SELECT ... FOR UPDATE
+                | "delete"
+                | "merge"
+                | "truncate"
+                | "into" // SELECT ... INTO creates a table
+                | "create"
+                | "alter"
+                | "drop"
+                | "nextval"
+                | "setval"
+                | "share" // SELECT ... FOR SHARE / FOR KEY SHARE
+                | "lock"
+        )
+    })
+}
+
+// Whether the SQL uses the `$n` parameter, `$1` inside `$10` doesn't count
+pub fn references_param(sql: &str, param: usize) -> bool {
+    let placeholder = format!("${}", param);
+    sql.match_indices(&placeholder)
+        .any(|(at, _)| !sql[at + placeholder.len()..].starts_with(|c: char| c.is_ascii_digit()))
+}
+
+// Statements whose result depends on when or how often they run, never cached automatically.
+// Like is_read_only this also matches the names inside string literals, which errs on the safe side.
+pub fn is_volatile(sql: &str) -> bool {
+    sql.to_ascii_lowercase()
+        .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .any(|word| {
+            matches!(
+                word,
+                "now"
+                    | "random"
+                    | "clock_timestamp"
+                    | "statement_timestamp"
+                    | "transaction_timestamp"
+                    | "timeofday"
+                    | "current_timestamp"
+                    | "current_time"
+                    | "current_date"
+                    | "localtime"
+                    | "localtimestamp"
+                    | "gen_random_uuid"
+                    | "uuid_generate_v4"
+                    | "txid_current"
+                    | "pg_sleep"
+            )
+        })
+}
+
+pub struct Fingerprint {
+    pub text: String,
+    pub has_literals: bool, // Literal values were replaced, the SQL text changes with them
+}
+
+// Groups statements that only differ in whitespace and literal values. String and number literals
+// become `?`, parameters like $1 and quoted identifiers are kept.
+pub fn fingerprint(sql: &str) -> Fingerprint {
+    let mut text = String::with_capacity(sql.len());
+    let mut has_literals = false;
+    let mut in_word = false; // Digits inside identifiers and parameters aren't literals
+    let mut chars = sql.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                // '' is an escaped quote inside the literal
+                while let Some(c) = chars.next() {
+                    if c == '\'' && chars.next_if_eq(&'\'').is_none() {
+                        break;
+                    }
+                }
+                text.push('?');
+                has_literals = true;
+            }
+            '"' => {
+                text.push(c);
+                for c in chars.by_ref() {
+                    text.push(c);
+                    if c == '"' {
+                        break;
+                    }
+                }
+            }
+            c if c.is_whitespace() => {
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                text.push(' ');
+            }
+            c if c.is_ascii_digit() && !in_word => {
+                while chars
+                    .next_if(|c| c.is_ascii_alphanumeric() || *c == '.')
+                    .is_some()
+                {}
+                text.push('?');
+                has_literals = true;
+            }
+            c => text.push(c),
+        }
+        in_word = c.is_alphanumeric() || c == '_' || c == '$';
+    }
+    Fingerprint { text, has_literals }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_are_read_only() {
+        assert!(is_read_only("SELECT * FROM users WHERE id = $1"));
+        assert!(is_read_only(
+            "  with recent AS (SELECT * FROM posts) SELECT * FROM recent"
+        ));
+        assert!(is_read_only("VALUES (1), (2)"));
+        assert!(is_read_only("SHOW server_version"));
+    }
+
+    #[test]
+    fn writes_and_locks_are_not_read_only() {
+        assert!(!is_read_only("INSERT INTO users (name) VALUES ($1)"));
+        assert!(!is_read_only("UPDATE users SET name = $1"));
+        assert!(!is_read_only(
+            "WITH gone AS (DELETE FROM users RETURNING id) SELECT * FROM gone"
+        ));
+        assert!(!is_read_only("SELECT * FROM users FOR UPDATE"));
+        assert!(!is_read_only("SELECT * FROM users FOR KEY SHARE"));
+        assert!(!is_read_only("SELECT * INTO backup FROM users"));
+        assert!(!is_read_only("SELECT nextval('users_id_seq')"));
+        assert!(!is_read_only("TRUNCATE users"));
+        assert!(!is_read_only(""));
+    }
+
+    #[test]
+    fn keywords_in_literals_count_as_writes() {
+        assert!(!is_read_only("SELECT * FROM posts WHERE title = 'update'"));
+    }
+
+    #[test]
+    fn params_are_matched_exactly() {
+        assert!(references_param("SELECT * FROM users WHERE id = $1", 1));
+        assert!(references_param("SELECT $2::int, $1", 2));
+        assert!(!references_param("SELECT * FROM users WHERE id = $10", 1));
+        assert!(references_param("SELECT $10, $1", 1));
+        assert!(!references_param("SELECT * FROM users WHERE id = $1", 2));
+    }
+
+    #[test]
+    fn fingerprints_replace_literals() {
+        let fingerprint = fingerprint("SELECT * FROM users WHERE id = 42 AND score > 3.5e2");
+        assert_eq!(
+            fingerprint.text,
+            "SELECT * FROM users WHERE id = ? AND score > ?"
+        );
+        assert!(fingerprint.has_literals);
+    }
+
+    #[test]
+    fn fingerprints_replace_strings_with_escaped_quotes() {
+        let fingerprint = fingerprint("SELECT * FROM users WHERE name = 'O''Brien' AND x = ''");
+        assert_eq!(
+            fingerprint.text,
+            "SELECT * FROM users WHERE name = ? AND x = ?"
+        );
+    }
+
+    #[test]
+    fn fingerprints_keep_params_and_identifiers() {
+        let sql = "SELECT t2.col1, \"Col 2\" FROM t2 WHERE id = $1 AND v = $12";
+        let fingerprint = fingerprint(sql);
+        assert_eq!(fingerprint.text, sql);
+        assert!(!fingerprint.has_literals);
+    }
+
+    #[test]
+    fn fingerprints_fold_whitespace() {
+        let fingerprint = fingerprint("  SELECT *\n\tFROM   users\r\nWHERE id = $1 ");
+        assert_eq!(fingerprint.text, "SELECT * FROM users WHERE id = $1");
+    }
+
+    #[test]
+    fn statements_that_differ_in_literals_share_a_fingerprint() {
+        assert_eq!(
+            fingerprint("SELECT * FROM posts WHERE user_id = 1 AND title = 'a'").text,
+            fingerprint("SELECT * FROM posts  WHERE user_id = 1234 AND title = 'b c'").text
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use futures::future::join_all;
+use serde::Deserialize;
+use sqlx::{PgPool, Row};
+use tracing::{info, warn};
+
+use crate::config::DatabaseConfig;
+use crate::database::pool;
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicaStrategy {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+}
+
+// A primary and its streaming replicas. Reads are routed to healthy replicas, everything else and
+// every read while no replica is healthy goes to the primary.
+pub struct Upstream {
+    pub name: String,
+    pub primary: PgPool,
+    replicas: Vec<Replica>,
+    strategy: ReplicaStrategy,
+    max_lag_secs: Option<f64>,
+    next: AtomicUsize, // Round robin cursor
+}
+
+pub struct Replica {
+    pub name: String, // host:port/database, the URL may hold credentials
+    pub pool: PgPool,
+    healthy: AtomicBool,
+    lag_ms: AtomicU64,
+}
+
+impl Replica {
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn lag(&self) -> Duration {
+        Duration::from_millis(self.lag_ms.load(Ordering::Relaxed))
+    }
+}
+
+const REPLICA_LAG_QUERY: &str = "SELECT CASE
+        WHEN NOT pg_is_in_recovery() THEN 0
+        WHEN pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0
+        ELSE COALESCE(EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()), 0)
+    END::float8 AS lag";
+
+impl Upstream {
+    pub async fn connect(name: &str, config: &DatabaseConfig) -> Result<Self, sqlx::Error> {
+        let primary = pool::connect(config, &config.url).await?;
+
+        // Replicas start out unhealthy and join once the first health check has passed
+        let mut replicas = Vec::with_capacity(config.replicas.len());
+        for replica in &config.replicas {
+            let pool = pool::connect_lazy(config, &replica.url)?;
+            replicas.push(Replica {
+                name: pool::label(&pool),
+                pool,
+                healthy: AtomicBool::new(false),
+                lag_ms: AtomicU64::new(0),
+            });
+        }
+
+        Ok(Upstream {
+            name: name.to_string(),
+            primary,
+            replicas,
+            strategy: config.replica_strategy.unwrap_or_default(),
+            max_lag_secs: config.max_replica_lag_secs,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn writer(&self) -> &PgPool {
+        &self.primary
+    }
+
+    pub fn reader(&self) -> &PgPool {
+        let healthy: Vec<&Replica> = self.replicas.iter().filter(|r| r.is_healthy()).collect();
+        if healthy.is_empty() {
+            return &self.primary;
+        }
+
+        match self.strategy {
+            ReplicaStrategy::RoundRobin => {
+                let index = self.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+                &healthy[index].pool
+            }
+            ReplicaStrategy::LeastConnections => {
+                &healthy
+                    .iter()
+                    // size and num_idle are read separately and may briefly disagree
+                    .min_by_key(|r| (r.pool.size() as usize).saturating_sub(r.pool.num_idle()))
+                    .unwrap()
+                    .pool
+            }
+        }
+    }
+
+    pub async fn close(&self) {
+        self.primary.close().await;
+        for replica in &self.replicas {
+            replica.pool.close().await;
+        }
+    }
+
+    pub fn replicas(&self) -> &[Replica] {
+        &self.replicas
+    }
+
+    pub async fn check_replicas(&self) {
+        join_all(
+            self.replicas
+                .iter()
+                .map(|replica| self.check_replica(replica)),
+        )
+        .await;
+    }
+
+    async fn check_replica(&self, replica: &Replica) {
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            sqlx::query(REPLICA_LAG_QUERY).fetch_one(&replica.pool),
+        )
+        .await;
+
+        let healthy = match result {
+            Ok(Ok(row)) => {
+                let lag: f64 = row.get("lag");
+                replica
+                    .lag_ms
+                    .store((lag * 1_000.0) as u64, Ordering::Relaxed);
+                match self.max_lag_secs {
+                    Some(max_lag) if lag > max_lag => {
+                        warn!(
+                            "Replica {} is {:.1}s behind the primary, routing reads elsewhere",
+                            replica.name, lag
+                        );
+                        false
+                    }
+                    _ => true,
+                }
+            }
+            Ok(Err(err)) => {
+                warn!("Replica {} health check failed: {}", replica.name, err);
+                false
+            }
+            Err(_) => {
+                warn!("Replica {} health check timed out", replica.name);
+                false
+            }
+        };
+
+        let was_healthy = replica.healthy.swap(healthy, Ordering::Relaxed);
+        if healthy && !was_healthy {
+            info!("Replica {} is healthy, routing reads to it", replica.name);
+        }
+    }
+
+    pub fn spawn_health_checks(self: &Arc<Self>, interval: Duration) {
+        if self.replicas.is_empty() {
+            return;
+        }
+        let upstream = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                // Pools are closed on shutdown
+                if upstream.primary.is_closed() {
+                    break;
+                }
+                upstream.check_replicas().await;
+            }
+        });
+    }
+}
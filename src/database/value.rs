@@ -15,6 +15,32 @@ pub enum PostcardValue {
     Null,
 }
 
+impl PostcardValue {
+    // Rough size of the value once serialized to JSON, used to enforce byte limits while streaming
+    pub fn estimated_size(&self) -> usize {
+        match self {
+            PostcardValue::Object(fields) => {
+                2 + fields
+                    .iter()
+                    .map(|(k, v)| k.len() + 4 + v.estimated_size())
+                    .sum::<usize>()
+            }
+            PostcardValue::Array(arr) => {
+                2 + arr.iter().map(|v| v.estimated_size() + 1).sum::<usize>()
+            }
+            PostcardValue::String(s) => s.len() + 2,
+            PostcardValue::Integer8(_) => 4,
+            PostcardValue::Integer16(_) => 6,
+            PostcardValue::Integer32(_) => 11,
+            PostcardValue::Integer64(_) => 20,
+            PostcardValue::Float32(_) => 16,
+            PostcardValue::Float64(_) => 24,
+            PostcardValue::Bool(_) => 5,
+            PostcardValue::Null => 4,
+        }
+    }
+}
+
 // Manual Serialize so JSON looks normal
 // impl Serialize for PostcardValue {
 //     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
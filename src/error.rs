@@ -0,0 +1,152 @@
+use axum::Json;
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use sqlx::postgres::PgDatabaseError;
+
+use crate::metrics::METRICS;
+
+// JSON body returned for every failed request. `code` is the Postgres SQLSTATE when the error
+// came from the database, otherwise a Pledge specific snake_case code.
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    pub detail: Option<String>,
+    pub hint: Option<String>,
+    pub constraint: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PledgeError {
+    pub status: StatusCode,
+    pub body: Box<ErrorBody>, // Boxed to keep Result<_, PledgeError> small
+}
+
+impl PledgeError {
+    pub fn new(status: StatusCode, code: &str, message: impl Into<String>) -> Self {
+        PledgeError {
+            status,
+            body: Box::new(ErrorBody {
+                code: code.to_string(),
+                message: message.into(),
+                detail: None,
+                hint: None,
+                constraint: None,
+            }),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", message)
+    }
+}
+
+impl From<sqlx::Error> for PledgeError {
+    fn from(err: sqlx::Error) -> Self {
+        let error = match err {
+            sqlx::Error::Database(db_err) => {
+                let code = db_err.code().map(|c| c.into_owned()).unwrap_or_default();
+                let mut error = PledgeError::new(
+                    status_for_sqlstate(&code),
+                    &code,
+                    db_err.message().to_string(),
+                );
+                if let Some(pg_err) = db_err.try_downcast_ref::<PgDatabaseError>() {
+                    error.body.detail = pg_err.detail().map(str::to_string);
+                    error.body.hint = pg_err.hint().map(str::to_string);
+                    error.body.constraint = pg_err.constraint().map(str::to_string);
+                }
+                error
+            }
+            sqlx::Error::PoolTimedOut => PledgeError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "pool_timeout",
+                "Timed out waiting for a database connection",
+            ),
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::Protocol(_)
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed => PledgeError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "connection_error",
+                err.to_string(),
+            ),
+            _ => PledgeError::internal(err.to_string()),
+        };
+        METRICS.record_upstream_error(&error);
+        error
+    }
+}
+
+impl From<JsonRejection> for PledgeError {
+    fn from(rejection: JsonRejection) -> Self {
+        PledgeError::new(rejection.status(), "invalid_body", rejection.body_text())
+    }
+}
+
+impl IntoResponse for PledgeError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self.body)).into_response()
+    }
+}
+
+// Maps a SQLSTATE to the HTTP status the client should see, see
+// https://www.postgresql.org/docs/current/errcodes-appendix.html
+pub fn status_for_sqlstate(code: &str) -> StatusCode {
+    match code {
+        "23505" | "23P01" => StatusCode::CONFLICT, // unique_violation, exclusion_violation
+        "40001" | "40P01" => StatusCode::CONFLICT, // serialization_failure, deadlock_detected
+        "57014" => StatusCode::GATEWAY_TIMEOUT,    // query_canceled (statement_timeout)
+        "25006" => StatusCode::FORBIDDEN, // read_only_sql_transaction, a write without allow_writes
+        "57P01" | "57P02" | "57P03" => StatusCode::SERVICE_UNAVAILABLE, // Server shutting down
+        _ => match code.get(0..2) {
+            Some("22") | Some("23") | Some("42") | Some("0A") => StatusCode::BAD_REQUEST,
+            Some("08") | Some("53") => StatusCode::SERVICE_UNAVAILABLE, // Connection, resources
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_sqlstates_to_statuses() {
+        assert_eq!(status_for_sqlstate("23505"), StatusCode::CONFLICT);
+        assert_eq!(status_for_sqlstate("40P01"), StatusCode::CONFLICT);
+        assert_eq!(status_for_sqlstate("57014"), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(status_for_sqlstate("25006"), StatusCode::FORBIDDEN);
+        assert_eq!(
+            status_for_sqlstate("57P01"),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_sqlstate_class() {
+        assert_eq!(status_for_sqlstate("23503"), StatusCode::BAD_REQUEST);
+        assert_eq!(status_for_sqlstate("22P02"), StatusCode::BAD_REQUEST);
+        assert_eq!(status_for_sqlstate("42P01"), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            status_for_sqlstate("08006"),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            status_for_sqlstate("53300"),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            status_for_sqlstate("XX000"),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(status_for_sqlstate(""), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,143 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::extract::rejection::JsonRejection;
+use axum::http::{HeaderMap, StatusCode};
+use axum::{Extension, Json};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tracing::{Instrument, debug, field, info_span};
+
+use crate::auth::Identity;
+use crate::cache::control::CacheDirectives;
+use crate::cache::pagination::PageRequest;
+use crate::error::{ErrorBody, PledgeError};
+use crate::handlers::query::{
+    QueryOptions, QueryResponse, postcard_to_json, resolve_sql, run_query,
+};
+use crate::server::state::AppState;
+
+const MAX_BATCH_ITEMS: usize = 100;
+
+// Each item names either raw SQL or a template from pledge.toml
+#[derive(Deserialize)]
+pub struct BatchItem {
+    sql: Option<String>,
+    name: Option<String>,
+    #[serde(default)]
+    params: Vec<serde_json::Value>,
+    database: Option<String>,
+    timeout_ms: Option<u64>,
+    page_size: Option<usize>,
+    cursor: Option<String>,
+    cache: Option<CacheDirectives>,
+}
+
+#[derive(Serialize)]
+pub struct BatchResponse {
+    results: Vec<BatchItemResult>,
+}
+
+#[derive(Serialize)]
+pub struct BatchItemResult {
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rows: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool, // Rows were cut off at max_rows / max_response_bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>, // Set on paginated results with more pages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorBody>,
+}
+
+pub async fn batch_handler(
+    State(state): State<AppState>,
+    identity: Option<Extension<Arc<Identity>>>,
+    headers: HeaderMap,
+    body: Result<Json<Vec<BatchItem>>, JsonRejection>,
+) -> Result<Json<BatchResponse>, PledgeError> {
+    let Json(items) = body?;
+    if items.len() > MAX_BATCH_ITEMS {
+        return Err(PledgeError::bad_request(format!(
+            "A batch may contain at most {} queries",
+            MAX_BATCH_ITEMS
+        )));
+    }
+    debug!(items = items.len(), "Received batch");
+
+    // Cache hits resolve immediately, misses run concurrently and are bounded by the pool size
+    let identity = identity.map(|Extension(identity)| identity);
+    let results = join_all(items.into_iter().enumerate().map(|(index, item)| {
+        // Each item records its own template and cache result
+        let span = info_span!(
+            "batch_item",
+            index,
+            template = field::Empty,
+            cache = field::Empty
+        );
+        run_item(&state, &headers, identity.clone(), item).instrument(span)
+    }))
+    .await;
+
+    Ok(Json(BatchResponse {
+        results: results
+            .into_iter()
+            .map(|result| match result {
+                Ok((response, etag)) => BatchItemResult {
+                    status: StatusCode::OK.as_u16(),
+                    rows: Some(response.rows.iter().map(postcard_to_json).collect()),
+                    truncated: response.truncated,
+                    next_cursor: response.next_cursor,
+                    etag: Some(etag),
+                    error: None,
+                },
+                Err(err) => BatchItemResult {
+                    status: err.status.as_u16(),
+                    rows: None,
+                    truncated: false,
+                    next_cursor: None,
+                    etag: None,
+                    error: Some(*err.body),
+                },
+            })
+            .collect(),
+    }))
+}
+
+async fn run_item(
+    state: &AppState,
+    headers: &HeaderMap,
+    identity: Option<Arc<Identity>>,
+    item: BatchItem,
+) -> Result<(QueryResponse, String), PledgeError> {
+    let (sql, template) = resolve_sql(state, item.sql, item.name)?;
+    if let (Some(template), Some(database)) = (template, item.database.as_deref())
+        && state.matcher.database_of(template) != Some(database)
+    {
+        return Err(PledgeError::bad_request(format!(
+            "Query '{}' belongs to another database than '{}'",
+            template.name, database
+        )));
+    }
+    let options = QueryOptions {
+        template,
+        database: item.database.or_else(|| {
+            template
+                .and_then(|t| state.matcher.database_of(t))
+                .map(str::to_string)
+        }),
+        timeout_ms: item.timeout_ms,
+        page: PageRequest {
+            size: item.page_size,
+            cursor: item.cursor,
+        },
+        cache: CacheDirectives::resolve(headers, item.cache),
+        identity,
+    };
+    let result = run_query(state, &sql, &item.params, &options).await?;
+    let etag = result.etag.clone();
+    Ok((result.into_response()?, etag))
+}
@@ -1,13 +1,160 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
 use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use futures::future::join_all;
 use serde::Serialize;
 
+use crate::database::upstream::Upstream;
+use crate::server::state::AppState;
+
+const READY_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
 #[derive(Serialize)]
 pub struct HealthResponse {
     pub status: String,
 }
 
+// Liveness, only says the process is up and serving. Also served as /health.
 pub async fn health_handler() -> Json<HealthResponse> {
     Json(HealthResponse {
         status: "OK".to_string(),
     })
 }
+
+// Ordered from best to worst, so the overall status is the max of its parts
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum ComponentStatus {
+    Ok,
+    Degraded, // Still serving, e.g. reads fall back to the primary
+    Down,
+}
+
+#[derive(Serialize)]
+pub struct ReadyResponse {
+    status: ComponentStatus,
+    databases: BTreeMap<String, DatabaseHealth>,
+    cache: CacheHealth,
+}
+
+#[derive(Serialize)]
+pub struct DatabaseHealth {
+    status: ComponentStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<f64>, // Acquiring a connection and running SELECT 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    connections: u32,
+    idle_connections: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    replicas: Vec<ReplicaHealth>,
+}
+
+#[derive(Serialize)]
+pub struct ReplicaHealth {
+    name: String,
+    status: ComponentStatus,
+    lag_secs: f64, // As of the last replica health check
+}
+
+#[derive(Serialize)]
+pub struct CacheHealth {
+    entries: u64,
+    weighted_size_bytes: u64,
+    capacity_bytes: Option<u64>,
+    utilization: Option<f64>,
+}
+
+// Readiness, 503 as soon as any component isn't fully healthy so load balancers stop routing here.
+// With server.ready_when_degraded only a database that is down does, reads still fall back to the primary.
+pub async fn ready_handler(State(state): State<AppState>) -> (StatusCode, Json<ReadyResponse>) {
+    let upstreams: Vec<_> = state.databases.iter().collect();
+    let checks = join_all(upstreams.iter().map(|upstream| check_database(upstream))).await;
+    let databases: BTreeMap<String, DatabaseHealth> = upstreams
+        .iter()
+        .map(|upstream| upstream.name.clone())
+        .zip(checks)
+        .collect();
+
+    // Pending evictions and inserts are applied first so the sizes are current
+    state.cache.run_pending_tasks();
+    let weighted_size = state.cache.weighted_size();
+    let capacity = state.cache.policy().max_capacity();
+    let cache = CacheHealth {
+        entries: state.cache.entry_count(),
+        weighted_size_bytes: weighted_size,
+        capacity_bytes: capacity,
+        utilization: capacity.map(|capacity| weighted_size as f64 / capacity as f64),
+    };
+
+    let status = databases
+        .values()
+        .map(|database| database.status)
+        .max()
+        .unwrap_or(ComponentStatus::Ok);
+    let code = match status {
+        ComponentStatus::Ok => StatusCode::OK,
+        ComponentStatus::Degraded if state.ready_when_degraded => StatusCode::OK,
+        ComponentStatus::Degraded | ComponentStatus::Down => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (
+        code,
+        Json(ReadyResponse {
+            status,
+            databases,
+            cache,
+        }),
+    )
+}
+
+async fn check_database(upstream: &Upstream) -> DatabaseHealth {
+    let pool = upstream.writer();
+    let started = Instant::now();
+    let result = tokio::time::timeout(READY_CHECK_TIMEOUT, async {
+        let mut conn = pool.acquire().await?;
+        sqlx::query("SELECT 1").execute(&mut *conn).await
+    })
+    .await;
+    let (mut status, latency_ms, error) = match result {
+        Ok(Ok(_)) => (
+            ComponentStatus::Ok,
+            Some(started.elapsed().as_secs_f64() * 1_000.0),
+            None,
+        ),
+        Ok(Err(err)) => (ComponentStatus::Down, None, Some(err.to_string())),
+        Err(_) => (
+            ComponentStatus::Down,
+            None,
+            Some(format!(
+                "No response within {}ms",
+                READY_CHECK_TIMEOUT.as_millis()
+            )),
+        ),
+    };
+
+    let replicas: Vec<ReplicaHealth> = upstream
+        .replicas()
+        .iter()
+        .map(|replica| ReplicaHealth {
+            name: replica.name.clone(),
+            status: match replica.is_healthy() {
+                true => ComponentStatus::Ok,
+                false => ComponentStatus::Degraded,
+            },
+            lag_secs: replica.lag().as_secs_f64(),
+        })
+        .collect();
+    status = replicas.iter().map(|r| r.status).fold(status, Ord::max);
+
+    DatabaseHealth {
+        status,
+        latency_ms,
+        error,
+        connections: pool.size(),
+        idle_connections: pool.num_idle(),
+        replicas,
+    }
+}
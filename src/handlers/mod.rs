@@ -1,2 +1,5 @@
+pub mod batch;
 pub mod health;
 pub mod query;
+pub mod stats;
+pub mod transaction;
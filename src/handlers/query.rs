@@ -1,144 +1,472 @@
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use crate::auth::jwt::bind_claims;
+use crate::auth::{self, Identity};
+use crate::cache::QueryTemplate;
+use crate::cache::auto::AUTO_TEMPLATE;
+use crate::cache::control::CacheDirectives;
+use crate::cache::pagination::{self, PageRequest};
+use crate::cache::store::{
+    CacheEntry, cache_key, content_etag, etag_matches, invalidate_templates,
+};
+use crate::config::LimitAction;
+use crate::database::executor::{ResultLimits, execute_cancellable};
+use crate::database::statement;
 use crate::database::value::PostcardValue;
+use crate::error::PledgeError;
+use crate::logging::{log_slow_query, params_for_log};
+use crate::metrics::METRICS;
 use crate::server::state::AppState;
-use crate::{cache::store::cache_key, database::conversion};
-use axum::Json;
-use axum::http::{StatusCode, header};
+use axum::extract::rejection::JsonRejection;
+use axum::http::{HeaderMap, StatusCode, header};
 use axum::response::Response;
+use axum::{Extension, Json};
 use serde::{Deserialize, Serialize};
-use sqlx::{Column, PgPool, Row};
+use tracing::{Span, debug, info, info_span, warn};
 
 #[derive(Deserialize)]
 pub struct QueryRequest {
     sql: String,
     params: Vec<serde_json::Value>,
+    database: Option<String>,
+    timeout_ms: Option<u64>,
+    page_size: Option<usize>,
+    cursor: Option<String>,
+    cache: Option<CacheDirectives>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct QueryResponse {
-    rows: Vec<PostcardValue>,
+    pub rows: Vec<PostcardValue>,
+    pub truncated: bool, // Rows were cut off at max_rows / max_response_bytes
+    pub next_cursor: Option<String>, // Set on paginated results with more pages
 }
 
 pub async fn query_handler(
     axum::extract::State(state): axum::extract::State<AppState>,
-    Json(body): Json<QueryRequest>,
-) -> Result<Response, (StatusCode, String)> {
-    println!("Received query: {:}", body.sql);
-    println!("Params: {:?}", body.params);
-
-    let matched_template = state.matcher.find_template(&body.sql);
-    let key = cache_key(&body.sql, &body.params);
-
-    if matched_template.is_some() {
-        if let Some((cached_result, expiry)) = state.cache.get(&key) {
-            if Instant::now() < expiry {
-                //let bytes = cached_result.to_vec();
-                let query_response = postcard::from_bytes::<QueryResponse>(&cached_result)
-                    .map_err(|e| {
-                        (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            format!("Postcard error: {}", e),
-                        )
-                    })?;
-                let json_value = response_to_json(&query_response);
-                let json_bytes = serde_json::to_vec(&json_value)
-                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-                let response = Response::builder()
-                    .header(header::CONTENT_TYPE, "application/json")
-                    .body(json_bytes.into())
-                    .unwrap();
-                println!("✓ CACHE HIT (key: {})", &key[0..8]);
-                return Ok(response);
-            } else {
-                println!(
-                    "✓ CACHE HIT, but cache expired per entry level TTL, invalidating and retrieving new value"
-                );
-                state.cache.invalidate(&key);
-            }
-        }
+    identity: Option<Extension<Arc<Identity>>>,
+    headers: HeaderMap,
+    body: Result<Json<QueryRequest>, JsonRejection>,
+) -> Result<Response, PledgeError> {
+    let Json(body) = body?;
+    debug!(
+        sql = %body.sql,
+        params = %params_for_log(&body.params, state.log_params),
+        "Received query"
+    );
+
+    let options = QueryOptions {
+        template: None,
+        database: body.database,
+        timeout_ms: body.timeout_ms,
+        page: PageRequest {
+            size: body.page_size,
+            cursor: body.cursor,
+        },
+        cache: CacheDirectives::resolve(&headers, body.cache),
+        identity: identity.map(|Extension(identity)| identity),
+    };
+    let result = run_query(&state, &body.sql, &body.params, &options).await?;
+
+    // Also covers re-fetches that returned identical rows
+    if let Some(if_none_match) = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        && etag_matches(if_none_match, &result.etag)
+    {
+        return Ok(not_modified(&result.etag));
     }
 
-    // Cache miss path
-    println!("x CACHE MISS (key: {})", &key[0..8]);
-    let rows = execute_query(&state.pool, &body.sql, &body.params).await?;
+    let etag = result.etag.clone();
+    let age = result.age;
+    let json_bytes = info_span!("serialize", format = "json").in_scope(|| {
+        let json_value = response_to_json(&result.into_response()?);
+        serde_json::to_vec(&json_value).map_err(|e| PledgeError::internal(e.to_string()))
+    })?;
+
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::ETAG, &etag);
+    if let Some(age) = age {
+        response = response.header(header::AGE, age);
+    }
+    Ok(response.body(json_bytes.into()).unwrap())
+}
 
-    let response = QueryResponse { rows };
-    let cache_bytes = postcard::to_allocvec(&response)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    let json_value = response_to_json(&response);
-    let json_bytes = serde_json::to_vec(&json_value)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+// Result of running a query through the cache, either still postcard encoded from the cache
+// or freshly fetched from the database
+pub struct QueryResult {
+    body: QueryResultBody,
+    pub etag: String,
+    pub age: Option<u64>, // Seconds since the result was cached, None when fetched from the database
+}
 
-    if let Some(template) = matched_template {
-        let expiration = match template.ttl {
-            Some(ttl) => Instant::now() + Duration::from_secs(ttl),
-            None => Instant::now() + Duration::from_secs(state.global_ttl),
-        };
-        println!("[_] Stored in cache: {}", key);
-        state.cache.insert(key, (cache_bytes, expiration));
+enum QueryResultBody {
+    Cached(Vec<u8>),
+    Fresh(QueryResponse),
+}
+
+impl QueryResult {
+    pub fn into_response(self) -> Result<QueryResponse, PledgeError> {
+        match self.body {
+            QueryResultBody::Cached(data) => postcard::from_bytes::<QueryResponse>(&data)
+                .map_err(|e| PledgeError::internal(format!("Postcard error: {}", e))),
+            QueryResultBody::Fresh(response) => Ok(response),
+        }
     }
+}
 
-    Ok(Response::builder()
-        .header(header::CONTENT_TYPE, "application/json")
-        .body(json_bytes.into())
-        .unwrap())
+// Per-request settings shared by /query and /batch
+#[derive(Default)]
+pub struct QueryOptions<'a> {
+    pub template: Option<&'a QueryTemplate>, // Named by the request, otherwise matched by its SQL
+    pub database: Option<String>,
+    pub timeout_ms: Option<u64>, // Can only shorten the configured timeout
+    pub page: PageRequest,
+    pub cache: CacheDirectives,
+    pub identity: Option<Arc<Identity>>,
 }
 
-async fn execute_query(
-    pool: &PgPool,
+pub async fn run_query(
+    state: &AppState,
     sql: &str,
     params: &[serde_json::Value],
-) -> Result<Vec<PostcardValue>, (axum::http::StatusCode, String)> {
-    let mut query = sqlx::query(sql);
-
-    for param in params {
-        // Bind each parameter
-        if let Some(num) = param.as_i64() {
-            query = query.bind(num);
-        } else if let Some(text) = param.as_str() {
-            query = query.bind(text);
-        } else if let Some(bool) = param.as_bool() {
-            query = query.bind(bool);
-        } else if let Some(array) = param.as_array() {
-            query = query.bind(array);
-        } else if let Some(num) = param.as_f64() {
-            query = query.bind(num);
-        } else {
-            return Err((
-                axum::http::StatusCode::BAD_REQUEST,
-                "Unsupported parameter type".to_string(),
+    options: &QueryOptions<'_>,
+) -> Result<QueryResult, PledgeError> {
+    let started = Instant::now();
+    let directives = &options.cache;
+    // Several templates may share the same SQL, a template named by the request always wins
+    let matched_template = options.template.or_else(|| {
+        state
+            .matcher
+            .find_template(sql, options.database.as_deref())
+    });
+    let database = options
+        .database
+        .as_deref()
+        .or_else(|| matched_template.and_then(|t| state.matcher.database_of(t)));
+    if let Some(template) = matched_template {
+        Span::current().record("template", template.name.as_str());
+    }
+    let upstream = state.databases.get(database)?;
+    state
+        .auth
+        .authorize(options.identity.as_deref(), matched_template, sql)?;
+
+    // Bound claims become part of the params and so of the cache key
+    let bound_params;
+    let params = match matched_template {
+        Some(template) if !template.bind_claims.is_empty() => {
+            bound_params = bind_claims(&template.bind_claims, options.identity.as_deref(), params)?;
+            bound_params.as_slice()
+        }
+        _ => params,
+    };
+
+    // Paginated templates run as a rewritten query per page, so every page gets its own cache key.
+    // Without page_size or cursor the full result is returned, as before pagination was configured.
+    let page = match matched_template.and_then(|t| t.pagination.as_ref()) {
+        _ if !options.page.is_requested() => None,
+        Some(pagination) => Some(pagination::paginate(
+            sql,
+            params,
+            pagination,
+            &options.page,
+        )?),
+        None => {
+            return Err(PledgeError::bad_request(
+                "page_size and cursor are only supported on paginated templates",
             ));
         }
+    };
+    let (sql, params) = match &page {
+        Some(page) => (page.sql.as_str(), page.params.as_slice()),
+        None => (sql, params),
+    };
+
+    let session = state.auth.session(options.identity.as_deref());
+    let key = cache_key(&upstream.name, session.as_ref(), sql, params);
+    // Ad-hoc reads are cached under AUTO_TEMPLATE once auto_cache finds them hot enough
+    let auto = match matched_template {
+        None => state
+            .auto_cache
+            .as_deref()
+            .filter(|auto| auto.is_candidate(sql)),
+        Some(_) => None,
+    };
+    let cached_as = matched_template
+        .map(|t| t.name.as_str())
+        .or(auto.map(|_| AUTO_TEMPLATE));
+    let cacheable = cached_as.is_some() && !directives.bypass;
+    if !cacheable {
+        Span::current().record("cache", "bypass");
     }
 
-    let rows = query
-        .fetch_all(pool)
-        .await
-        .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-
-    let rows_to_return: Vec<PostcardValue> = rows
-        .into_iter()
-        .map(|row| {
-            let mut fields: Vec<(String, PostcardValue)> = Vec::new();
-            for (i, column) in row.columns().iter().enumerate() {
-                let value = conversion::convert_row_val_to_postcard(&row, column, i);
-                fields.push((column.name().to_string(), value));
+    // Etag and TTL of the entry a fetch replaces, adaptive TTLs are derived from them
+    let mut replaced = None;
+    if let Some(name) = cached_as
+        && cacheable
+        && let Some(entry) =
+            info_span!("cache_lookup", template = %name).in_scope(|| state.cache.get(&key))
+    {
+        let now = Instant::now();
+        if !directives.refresh && directives.accepts(&entry, now) {
+            debug!(key = &key[0..8], "Cache hit");
+            Span::current().record("cache", "hit");
+            METRICS.cache_hits.with_label_values(&[name]).inc();
+            if now >= entry.expires_at {
+                METRICS.cache_stale.with_label_values(&[name]).inc();
+            }
+            METRICS.observe_query(true, started.elapsed().as_secs_f64());
+            if let Some(template) = matched_template {
+                state.stats.record_hit(&template.name, entry.data.len());
             }
-            PostcardValue::Object(fields)
-        })
-        .collect();
+            return Ok(QueryResult {
+                age: Some(now.duration_since(entry.stored_at).as_secs()),
+                etag: entry.etag,
+                body: QueryResultBody::Cached(entry.data),
+            });
+        } else if now >= entry.expires_at {
+            debug!(
+                key = &key[0..8],
+                "Cached entry expired per template TTL, invalidating and fetching a new one"
+            );
+            state.cache.invalidate(&key);
+        }
+        replaced = Some((entry.etag, entry.expires_at - entry.stored_at));
+    }
+
+    if directives.only_if_cached {
+        return Err(PledgeError::new(
+            StatusCode::GATEWAY_TIMEOUT,
+            "not_cached",
+            "No acceptable cached result and only_if_cached was requested",
+        ));
+    }
+
+    // Cache miss path
+    debug!(key = &key[0..8], "Cache miss");
+    if let Some(name) = cached_as
+        && cacheable
+    {
+        Span::current().record("cache", "miss");
+        METRICS.cache_misses.with_label_values(&[name]).inc();
+    }
+    // Only reads of read-only templates may go to a replica, ad-hoc SQL always hits the primary
+    let pool = match matched_template {
+        Some(template) if template.is_read_only() => upstream.reader(),
+        _ => upstream.writer(),
+    };
+    let configured_timeout = matched_template
+        .and_then(|t| t.timeout_ms)
+        .or(state.query_timeout_ms);
+    let timeout = match (options.timeout_ms, configured_timeout) {
+        (Some(requested), Some(configured)) => Some(requested.min(configured)),
+        (requested, configured) => requested.or(configured),
+    }
+    .map(Duration::from_millis);
+    let limits = result_limits(state, matched_template, sql);
 
-    Ok(rows_to_return)
+    let session = session.as_ref();
+    let read_only =
+        matched_template.is_none() && auth::requires_read_only(options.identity.as_deref());
+    let attempted = Instant::now();
+    let fetched = match execute_cancellable(pool, sql, params, timeout, limits, session, read_only)
+        .await
+    {
+        // A replica that went away since its last health check shouldn't fail the read
+        Err(err)
+            if err.status == StatusCode::SERVICE_UNAVAILABLE
+                && !std::ptr::eq(pool, upstream.writer()) =>
+        {
+            warn!(
+                error = %err.body.message,
+                "Replica read failed, retrying on primary"
+            );
+            // The retry only gets what is left of the timeout
+            let remaining = timeout.map(|timeout| timeout.saturating_sub(attempted.elapsed()));
+            let writer = upstream.writer();
+            execute_cancellable(writer, sql, params, remaining, limits, session, read_only).await?
+        }
+        result => result?,
+    };
+
+    let elapsed = fetched.elapsed;
+    log_slow_query(
+        state,
+        matched_template.map(|t| t.name.as_str()),
+        sql,
+        params,
+        elapsed,
+    );
+
+    let mut rows = fetched.rows;
+    let next_cursor = match &page {
+        Some(page) => page.finish(&mut rows)?,
+        None => None,
+    };
+    let response = QueryResponse {
+        rows,
+        truncated: fetched.truncated,
+        next_cursor,
+    };
+    let cache_bytes = info_span!("serialize", format = "postcard")
+        .in_scope(|| postcard::to_allocvec(&response))
+        .map_err(|e| PledgeError::internal(e.to_string()))?;
+    let etag = content_etag(&cache_bytes);
+
+    // Candidates for new templates (see GET /stats/suggestions) and for auto_cache
+    let usage = match matched_template {
+        Some(template) => {
+            state
+                .stats
+                .record_fetch(&template.name, elapsed, cache_bytes.len());
+            None
+        }
+        None if statement::is_read_only(sql) => Some(state.fingerprints.record(
+            &upstream.name,
+            sql,
+            params,
+            elapsed,
+            cache_bytes.len(),
+            &etag,
+        )),
+        None => None,
+    };
+
+    if let Some(template) = matched_template
+        && !template.invalidates.is_empty()
+    {
+        invalidate_templates(&state.cache, &template.invalidates);
+    }
+    // Auto entries can't tell which writes affect them, so any write drops them all
+    let read_only =
+        matched_template.map_or_else(|| statement::is_read_only(sql), |t| t.is_read_only());
+    if state.auto_cache.is_some() && !read_only {
+        invalidate_templates(&state.cache, &[AUTO_TEMPLATE.to_string()]);
+    }
+
+    let too_large = state
+        .max_entry_bytes
+        .is_some_and(|max| cache_bytes.len() > max);
+    if too_large && cacheable {
+        info!(
+            key = &key[0..8],
+            bytes = cache_bytes.len(),
+            "Not caching result over max_entry_bytes"
+        );
+    }
+
+    let ttl = match (matched_template, auto, usage) {
+        (Some(template), _, _) => {
+            let previous = replaced.as_ref().map(|(etag, ttl)| (etag.as_str(), *ttl));
+            Some(template.next_ttl(state.global_ttl, previous, &etag))
+        }
+        (None, Some(auto), Some(usage)) if cacheable && !too_large => {
+            auto.admit(&usage, cache_bytes.len()).then_some(auto.ttl)
+        }
+        _ => None,
+    };
+    if let Some(name) = cached_as
+        && let Some(ttl) = ttl
+        && cacheable
+        && !too_large
+    {
+        let now = Instant::now();
+        debug!(
+            key = &key[0..8],
+            ttl_secs = ttl.as_secs(),
+            "Stored in cache"
+        );
+        if let Some(template) = matched_template {
+            state.stats.record_ttl(&template.name, ttl);
+        }
+        state.cache.insert(
+            key,
+            CacheEntry {
+                data: cache_bytes,
+                stored_at: now,
+                expires_at: now + ttl,
+                etag: etag.clone(),
+                template: name.to_string(),
+            },
+        );
+    }
+
+    METRICS.observe_query(false, started.elapsed().as_secs_f64());
+    Ok(QueryResult {
+        body: QueryResultBody::Fresh(response),
+        etag,
+        age: None,
+    })
+}
+
+// Requests may carry raw SQL or the name of a template from pledge.toml
+// and also returns the template when it was referenced by name
+pub fn resolve_sql(
+    state: &AppState,
+    sql: Option<String>,
+    name: Option<String>,
+) -> Result<(String, Option<&QueryTemplate>), PledgeError> {
+    match (sql, name) {
+        (Some(sql), None) => Ok((sql, None)),
+        (None, Some(name)) => match state.matcher.find_template_by_name(&name) {
+            Some(template) => Ok((template.sql.clone(), Some(template))),
+            None => Err(PledgeError::new(
+                StatusCode::NOT_FOUND,
+                "unknown_template",
+                format!("No query template named '{}'", name),
+            )),
+        },
+        _ => Err(PledgeError::bad_request(
+            "Each query needs exactly one of 'sql' or 'name'",
+        )),
+    }
+}
+
+fn not_modified(etag: &str) -> Response {
+    Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .header(header::ETAG, etag)
+        .body(axum::body::Body::empty())
+        .unwrap()
 }
 
-fn response_to_json(response: &QueryResponse) -> serde_json::Value {
-    serde_json::json!({
+// Limits only apply to reads. Stopping a write early cancels it on the backend, while the
+// response would still report the rows it returned, so writes always run to completion.
+pub fn result_limits(
+    state: &AppState,
+    template: Option<&QueryTemplate>,
+    sql: &str,
+) -> ResultLimits {
+    let read_only = template.map_or_else(|| statement::is_read_only(sql), |t| t.is_read_only());
+    if !read_only {
+        return ResultLimits::default();
+    }
+    ResultLimits {
+        max_rows: template.and_then(|t| t.max_rows).or(state.limits.max_rows),
+        max_bytes: template
+            .and_then(|t| t.max_response_bytes)
+            .or(state.limits.max_response_bytes),
+        truncate: state.limits.on_limit == LimitAction::Truncate,
+    }
+}
+
+pub fn response_to_json(response: &QueryResponse) -> serde_json::Value {
+    let mut json = serde_json::json!({
         "rows": response.rows.iter().map(postcard_to_json).collect::<Vec<_>>()
-    })
+    });
+    if response.truncated {
+        json["truncated"] = serde_json::Value::Bool(true);
+    }
+    if let Some(cursor) = &response.next_cursor {
+        json["next_cursor"] = serde_json::Value::String(cursor.clone());
+    }
+    json
 }
 
-fn postcard_to_json(val: &PostcardValue) -> serde_json::Value {
+pub fn postcard_to_json(val: &PostcardValue) -> serde_json::Value {
     match val {
         PostcardValue::Object(fields) => {
             let mut map = serde_json::Map::new();
@@ -0,0 +1,104 @@
+use std::fmt::Write;
+use std::sync::Arc;
+
+use axum::extract::{Query, State};
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use serde::Deserialize;
+
+use crate::auth::{self, Identity};
+use crate::error::PledgeError;
+use crate::server::state::AppState;
+use crate::stats::{Suggestion, TemplateReport};
+
+// GET /stats/templates, admin only. Busiest templates first.
+pub async fn template_stats_handler(
+    State(state): State<AppState>,
+    identity: Option<Extension<Arc<Identity>>>,
+) -> Result<Json<Vec<TemplateReport>>, PledgeError> {
+    auth::require_admin(identity.as_deref().map(Arc::as_ref))?;
+    let names = state
+        .matcher
+        .templates()
+        .map(|template| template.name.as_str());
+    Ok(Json(state.stats.report(names)))
+}
+
+#[derive(Deserialize)]
+pub struct SuggestionParams {
+    min_calls: Option<u64>, // Defaults to 10
+    limit: Option<usize>,   // Defaults to 20
+    #[serde(default)]
+    format: SuggestionFormat,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SuggestionFormat {
+    #[default]
+    Json,
+    Toml, // [[queries]] entries ready to paste into pledge.toml
+}
+
+// GET /stats/suggestions, admin only. Ad-hoc reads that took the most database time, as
+// template candidates with a suggested TTL.
+pub async fn suggestions_handler(
+    State(state): State<AppState>,
+    identity: Option<Extension<Arc<Identity>>>,
+    Query(params): Query<SuggestionParams>,
+) -> Result<Response, PledgeError> {
+    auth::require_admin(identity.as_deref().map(Arc::as_ref))?;
+    let suggestions = state
+        .fingerprints
+        .suggest(params.min_calls.unwrap_or(10), params.limit.unwrap_or(20));
+    match params.format {
+        SuggestionFormat::Json => Ok(Json(suggestions).into_response()),
+        SuggestionFormat::Toml => Ok((
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            suggestions_to_toml(&suggestions, state.matcher.default_database()),
+        )
+            .into_response()),
+    }
+}
+
+fn suggestions_to_toml(suggestions: &[Suggestion], default_database: Option<&str>) -> String {
+    let mut toml = String::new();
+    for suggestion in suggestions {
+        let ttl_reason = match suggestion.ttl_basis {
+            "changed" => "half the shortest time in which its result changed",
+            "unchanged" => "repeated calls returned the same result",
+            _ => "never repeated with the same parameters, adjust to how fresh it must be",
+        };
+        let _ = writeln!(
+            toml,
+            "# {} calls, {:.1}ms average, {:.1}s in the database. TTL: {}",
+            suggestion.calls,
+            suggestion.avg_ms,
+            suggestion.db_time_ms / 1000.0,
+            ttl_reason
+        );
+        if suggestion.has_literals {
+            let _ = writeln!(
+                toml,
+                "# The SQL has inline literals, send them as $n parameters so the template matches"
+            );
+        }
+        let _ = writeln!(toml, "[[queries]]");
+        let _ = writeln!(toml, "name = \"{}\"", suggestion.name);
+        let _ = writeln!(
+            toml,
+            "sql = {}",
+            toml::Value::String(suggestion.sql.clone())
+        );
+        if default_database != Some(suggestion.database.as_str()) {
+            let _ = writeln!(
+                toml,
+                "database = {}",
+                toml::Value::String(suggestion.database.clone())
+            );
+        }
+        let _ = writeln!(toml, "ttl = {}\n", suggestion.suggested_ttl);
+    }
+    toml
+}
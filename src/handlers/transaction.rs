@@ -0,0 +1,195 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, PgConnection};
+use tracing::debug;
+
+use crate::auth::jwt::bind_claims;
+use crate::auth::{self, Identity};
+use crate::cache::auto::AUTO_TEMPLATE;
+use crate::cache::store::invalidate_templates;
+use crate::database::executor::{CancelOnDrop, execute_query, set_read_only, with_timeout};
+use crate::database::statement;
+use crate::database::upstream::Upstream;
+use crate::error::{ErrorBody, PledgeError};
+use crate::handlers::query::{QueryResponse, resolve_sql, response_to_json, result_limits};
+use crate::logging::log_slow_query;
+use crate::server::state::AppState;
+
+const MAX_TRANSACTION_STATEMENTS: usize = 100;
+
+#[derive(Deserialize)]
+pub struct TransactionRequest {
+    statements: Vec<TransactionStatement>,
+    database: Option<String>, // All statements run on the same database
+}
+
+#[derive(Deserialize)]
+pub struct TransactionStatement {
+    sql: Option<String>,
+    name: Option<String>,
+    #[serde(default)]
+    params: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+pub struct TransactionResponse {
+    results: Vec<serde_json::Value>,
+}
+
+// Returned when the transaction was rolled back, `statement` is the index of the failing statement
+#[derive(Serialize)]
+pub struct TransactionError {
+    statement: Option<usize>,
+    error: ErrorBody,
+}
+
+// Runs all statements on one pinned connection inside a single transaction. Statements are never
+// served from the cache, and cache invalidation only happens once the transaction has committed.
+pub async fn transaction_handler(
+    State(state): State<AppState>,
+    identity: Option<Extension<Arc<Identity>>>,
+    body: Result<Json<TransactionRequest>, JsonRejection>,
+) -> Result<Json<TransactionResponse>, Response> {
+    let Json(body) = body.map_err(|e| rollback_response(None, e.into()))?;
+    if body.statements.len() > MAX_TRANSACTION_STATEMENTS {
+        return Err(rollback_response(
+            None,
+            PledgeError::bad_request(format!(
+                "A transaction may contain at most {} statements",
+                MAX_TRANSACTION_STATEMENTS
+            )),
+        ));
+    }
+    debug!(statements = body.statements.len(), "Received transaction");
+
+    let upstream = state
+        .databases
+        .get(body.database.as_deref())
+        .map_err(|e| rollback_response(None, e))?;
+    // Pinned so a statement can be cancelled on the backend when it times out or the client goes away
+    let mut pinned = CancelOnDrop::acquire(upstream.writer())
+        .await
+        .map_err(|e| rollback_response(None, e))?;
+    let identity = identity.as_deref().map(Arc::as_ref);
+    let outcome = run_statements(&state, upstream, identity, body.statements, pinned.conn()).await;
+    match &outcome {
+        // Still running or stopped early, dropping the armed guard cancels it
+        Err((_, err))
+            if err.status == StatusCode::GATEWAY_TIMEOUT
+                || err.status == StatusCode::PAYLOAD_TOO_LARGE => {}
+        _ => pinned.release(),
+    }
+    let (results, invalidations) =
+        outcome.map_err(|(statement, err)| rollback_response(statement, err))?;
+
+    if !invalidations.is_empty() {
+        invalidate_templates(&state.cache, &invalidations);
+    }
+
+    Ok(Json(TransactionResponse { results }))
+}
+
+// Runs every statement in one transaction on `conn`. Returns the results and the templates to
+// invalidate, or the index of the failing statement along with the error.
+async fn run_statements(
+    state: &AppState,
+    upstream: &Upstream,
+    identity: Option<&Identity>,
+    statements: Vec<TransactionStatement>,
+    conn: &mut PgConnection,
+) -> Result<(Vec<serde_json::Value>, Vec<String>), (Option<usize>, PledgeError)> {
+    let mut tx = conn.begin().await.map_err(|e| (None, e.into()))?;
+    // Any statement may be ad-hoc SQL, so the whole transaction is READ ONLY for such identities
+    if auth::requires_read_only(identity) {
+        set_read_only(&mut tx).await.map_err(|e| (None, e.into()))?;
+    }
+    if let Some(session) = state.auth.session(identity) {
+        session.apply(&mut tx).await.map_err(|e| (None, e.into()))?;
+    }
+
+    let mut results = Vec::with_capacity(statements.len());
+    let mut invalidations: Vec<String> = Vec::new();
+
+    for (index, statement) in statements.into_iter().enumerate() {
+        let failed = move |err: PledgeError| (Some(index), err);
+        let (sql, template) = resolve_sql(state, statement.sql, statement.name).map_err(failed)?;
+        if let Some(template) = template
+            && state.matcher.database_of(template) != Some(upstream.name.as_str())
+        {
+            return Err(failed(PledgeError::bad_request(format!(
+                "Query '{}' belongs to another database than this transaction",
+                template.name
+            ))));
+        }
+
+        let matched_template =
+            template.or_else(|| state.matcher.find_template(&sql, Some(&upstream.name)));
+        state
+            .auth
+            .authorize(identity, matched_template, &sql)
+            .map_err(failed)?;
+
+        let params = match matched_template {
+            Some(template) if !template.bind_claims.is_empty() => {
+                bind_claims(&template.bind_claims, identity, &statement.params).map_err(failed)?
+            }
+            _ => statement.params,
+        };
+
+        // Dropping `tx` on the error path rolls the transaction back
+        let limits = result_limits(state, matched_template, &sql);
+        let timeout = matched_template
+            .and_then(|t| t.timeout_ms)
+            .or(state.query_timeout_ms)
+            .map(Duration::from_millis);
+        let fetched = with_timeout(timeout, execute_query(&mut *tx, &sql, &params, limits))
+            .await
+            .map_err(failed)?;
+
+        log_slow_query(
+            state,
+            matched_template.map(|t| t.name.as_str()),
+            &sql,
+            &params,
+            fetched.elapsed,
+        );
+
+        if let Some(template) = matched_template {
+            invalidations.extend(template.invalidates.iter().cloned());
+        }
+        let read_only =
+            matched_template.map_or_else(|| statement::is_read_only(&sql), |t| t.is_read_only());
+        if state.auto_cache.is_some() && !read_only {
+            invalidations.push(AUTO_TEMPLATE.to_string());
+        }
+        results.push(response_to_json(&QueryResponse {
+            rows: fetched.rows,
+            truncated: fetched.truncated,
+            next_cursor: None,
+        }));
+    }
+
+    let commit = async { tx.commit().await.map_err(PledgeError::from) };
+    with_timeout(state.query_timeout_ms.map(Duration::from_millis), commit)
+        .await
+        .map_err(|e| (None, e))?;
+    Ok((results, invalidations))
+}
+
+fn rollback_response(statement: Option<usize>, err: PledgeError) -> Response {
+    (
+        err.status,
+        Json(TransactionError {
+            statement,
+            error: *err.body,
+        }),
+    )
+        .into_response()
+}
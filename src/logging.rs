@@ -0,0 +1,119 @@
+use std::io::{self, IsTerminal};
+use std::time::{Duration, Instant};
+
+use axum::extract::{MatchedPath, Request};
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry_sdk::trace::SdkTracer;
+use tracing::{Instrument, Level, field, warn};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry, fmt};
+
+use crate::config::{LogFormat, LoggingConfig};
+use crate::server::state::AppState;
+use crate::telemetry;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// RUST_LOG takes precedence over the [logging] section. Spans exported to `tracer` don't depend
+// on the log level.
+pub fn init(config: &LoggingConfig, tracer: Option<SdkTracer>) {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::new(
+            config
+                .filter
+                .as_deref()
+                .or(config.level.as_deref())
+                .unwrap_or("info"),
+        ),
+    };
+    let logs: Box<dyn Layer<Registry> + Send + Sync> = match config.format {
+        LogFormat::Text => fmt::layer().with_ansi(io::stdout().is_terminal()).boxed(),
+        LogFormat::Json => fmt::layer().json().flatten_event(true).boxed(),
+    };
+    let traces = tracer.map(|tracer| {
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(Targets::new().with_target("pledge", Level::INFO))
+    });
+    tracing_subscriber::registry()
+        .with(logs.with_filter(filter))
+        .with(traces)
+        .init();
+}
+
+// Wraps every routed request in a span carrying its id, so all logs of one request can be found.
+// run_query fills in the template and cache result.
+pub async fn trace_requests(request: Request, next: Next) -> Response {
+    // Keep the caller's id so logs can be followed across services
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %request.method(),
+        route = %route,
+        template = field::Empty,
+        cache = field::Empty,
+    );
+    telemetry::continue_trace(&span, request.headers());
+
+    let started = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+    span.in_scope(|| {
+        tracing::info!(
+            status = response.status().as_u16(),
+            latency_ms = started.elapsed().as_secs_f64() * 1_000.0,
+            "request completed"
+        )
+    });
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+// Parameter values may hold personal data and are only logged with logging.log_params
+pub fn params_for_log(params: &[serde_json::Value], log_params: bool) -> String {
+    match log_params {
+        true => serde_json::Value::from(params.to_vec()).to_string(),
+        false => format!("[{} redacted]", params.len()),
+    }
+}
+
+// Logs statements that took longer than logging.slow_query_ms. The "slow_query" target lets them
+// be filtered or routed apart from the other logs.
+pub fn log_slow_query(
+    state: &AppState,
+    template: Option<&str>,
+    sql: &str,
+    params: &[serde_json::Value],
+    elapsed: Duration,
+) {
+    if state.slow_query.is_none_or(|threshold| elapsed < threshold) {
+        return;
+    }
+    warn!(
+        target: "slow_query",
+        template = template.unwrap_or_default(),
+        sql,
+        params = %params_for_log(params, state.log_params),
+        elapsed_ms = elapsed.as_secs_f64() * 1000.0,
+        "Slow query"
+    );
+}
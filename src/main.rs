@@ -1,34 +1,51 @@
 use moka::sync::CacheBuilder;
-use sqlx::postgres::PgPoolOptions;
-use std::{
-    sync::Arc,
-    time::{Duration, Instant},
-};
+use std::{sync::Arc, time::Duration};
+use tracing::{error, info, warn};
 
+mod audit;
+mod auth;
 mod cache;
 mod config;
 mod database;
+mod error;
 mod handlers;
+mod logging;
+mod metrics;
 mod server;
+mod stats;
+mod telemetry;
+use auth::Authenticator;
+use cache::auto::AutoCache;
 pub use cache::matcher::QueryMatcher;
+use cache::store::CacheEntry;
+use database::registry::Databases;
 pub use server::state::AppState;
+use stats::{QueryFingerprints, TemplateStats};
 
 #[tokio::main]
 async fn main() {
     let config = config::load_config().expect("Failed to load config");
-    let pool = Arc::new(
-        PgPoolOptions::new()
-            .max_connections(5)
-            .connect(&config.database.url)
+    let telemetry = config.telemetry.as_ref().map(|telemetry| {
+        telemetry::init(telemetry).expect("Failed to set up OpenTelemetry export")
+    });
+    logging::init(
+        &config.logging,
+        telemetry.as_ref().map(|(_, tracer)| tracer.clone()),
+    );
+    let databases = Arc::new(
+        Databases::connect(&config)
             .await
             .expect("Failed to connect to database"),
     );
     let matcher = Arc::new(QueryMatcher::new(&config));
+    let auth = Arc::new(
+        Authenticator::new(config.auth.as_ref()).expect("Failed to load authentication config"),
+    );
 
     let max_ttl = config
         .queries
         .iter()
-        .filter_map(|q| q.ttl)
+        .map(|q| q.retention(config.cache.global_ttl))
         .max()
         .unwrap_or(config.cache.global_ttl)
         .max(config.cache.global_ttl);
@@ -38,35 +55,87 @@ async fn main() {
         None => 100 * 1_024 * 1_024, // Default to 100MiB cache size
     };
 
+    let auto_cache = config
+        .cache
+        .auto_cache
+        .as_ref()
+        .map(|auto| Arc::new(AutoCache::new(auto, cache_size)));
+    let max_ttl = match &auto_cache {
+        Some(auto) => max_ttl.max(auto.ttl.as_secs()),
+        None => max_ttl,
+    };
+
+    let released = auto_cache.clone();
     let cache = Arc::new(
         CacheBuilder::new(cache_size)
-            .weigher(|_key: &String, value: &(Vec<u8>, Instant)| {
-                value.0.len() as u32 // Weight by data size
+            .weigher(|_key: &String, value: &CacheEntry| {
+                value.data.len() as u32 // Weight by data size
             })
             .time_to_live(Duration::from_secs(max_ttl))
+            .support_invalidation_closures()
+            .eviction_listener(move |_key, value: CacheEntry, cause| {
+                metrics::METRICS.record_eviction(&value.template, cause);
+                if let Some(auto) = &released {
+                    auto.release(&value);
+                }
+            })
             .build(),
     );
 
-    println!("Cache initialized: {} MiB", cache_size / 1_024 / 1_024);
+    info!("Cache initialized: {} MiB", cache_size / 1_024 / 1_024);
+    if let Some(path) = &config.cache.snapshot_path
+        && std::path::Path::new(path).exists()
+    {
+        match cache::snapshot::load(&cache, &matcher, path) {
+            Ok(count) => info!("Loaded {} cache entries from {}", count, path),
+            Err(err) => warn!("Failed to load cache snapshot from {}: {}", path, err),
+        }
+    }
     {
         let sysinfo = sysinfo::System::new_all();
         let total_ram = sysinfo.total_memory();
         if (cache_size as f64) > (total_ram as f64 * 0.8) {
             // Using 80% of system RAM
-            eprintln!(
+            warn!(
                 "WARNING: Cache size {}MiB is close to total system RAM {}MiB",
                 cache_size / (1_024 * 1_024),
                 total_ram / (1_024 * 1_024)
             );
-            eprintln!("Consider reducing cache size or increasing system RAM");
+            warn!("Consider reducing cache size or increasing system RAM");
         }
     }
     let state = AppState {
-        pool,
+        databases,
         matcher,
         cache,
         global_ttl: config.cache.global_ttl,
+        query_timeout_ms: config.server.query_timeout_ms,
+        ready_when_degraded: config.server.ready_when_degraded.unwrap_or(false),
+        limits: config.limits.clone(),
+        max_entry_bytes: config.cache.max_entry_bytes,
+        auth,
+        log_params: config.logging.log_params,
+        slow_query: config.logging.slow_query_ms.map(Duration::from_millis),
+        stats: Arc::new(TemplateStats::default()),
+        fingerprints: Arc::new(QueryFingerprints::default()),
+        auto_cache,
     };
 
-    server::run_server(&config.server, state).await;
+    server::run_server(&config.server, state.clone()).await;
+
+    if let Some(path) = &config.cache.snapshot_path {
+        match cache::snapshot::save(&state.cache, path) {
+            Ok(count) => info!("Wrote {} cache entries to {}", count, path),
+            Err(err) => error!("Failed to write cache snapshot to {}: {}", path, err),
+        }
+    }
+    // Requests still running past the shutdown timeout hold on to their connections
+    match tokio::time::timeout(Duration::from_secs(5), state.databases.close()).await {
+        Ok(()) => info!("Closed database connections"),
+        Err(_) => warn!("Timed out closing database connections"),
+    }
+    // Sends the spans still buffered
+    if let Some((provider, _)) = telemetry {
+        let _ = tokio::task::spawn_blocking(move || provider.shutdown()).await;
+    }
 }
@@ -0,0 +1,222 @@
+use std::sync::Arc;
+use std::sync::LazyLock;
+
+use axum::Extension;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use moka::notification::RemovalCause;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use sqlx::PgPool;
+
+use crate::auth::{self, Identity};
+use crate::database::pool;
+use crate::error::PledgeError;
+use crate::server::state::AppState;
+
+// Process wide, so code without access to AppState (errors, the pool, eviction listener) can record
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    pub cache_hits: IntCounterVec,
+    pub cache_misses: IntCounterVec,
+    pub cache_stale: IntCounterVec,
+    cache_evictions: IntCounterVec,
+    cache_entries: IntGauge,
+    cache_weighted_size: IntGauge,
+    query_duration: HistogramVec,
+    pool_connections: IntGaugeVec,
+    pool_waiting: IntGaugeVec,
+    upstream_errors: IntCounterVec,
+    http_requests: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("pledge".to_string()), None).unwrap();
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            let counter = IntCounterVec::new(Opts::new(name, help), labels).unwrap();
+            registry.register(Box::new(counter.clone())).unwrap();
+            counter
+        };
+        let gauge_vec = |name: &str, help: &str, labels: &[&str]| {
+            let gauge = IntGaugeVec::new(Opts::new(name, help), labels).unwrap();
+            registry.register(Box::new(gauge.clone())).unwrap();
+            gauge
+        };
+        let gauge = |name: &str, help: &str| {
+            let gauge = IntGauge::new(name, help).unwrap();
+            registry.register(Box::new(gauge.clone())).unwrap();
+            gauge
+        };
+
+        let query_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "query_duration_seconds",
+                "Time to answer a query, by cache result",
+            )
+            .buckets(vec![
+                0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+                10.0,
+            ]),
+            &["cache"],
+        )
+        .unwrap();
+        registry.register(Box::new(query_duration.clone())).unwrap();
+
+        Metrics {
+            cache_hits: counter(
+                "cache_hits_total",
+                "Queries served from the cache",
+                &["template"],
+            ),
+            cache_misses: counter(
+                "cache_misses_total",
+                "Template queries that went to the database",
+                &["template"],
+            ),
+            cache_stale: counter(
+                "cache_stale_total",
+                "Entries served past their TTL because the client's max_age allowed it",
+                &["template"],
+            ),
+            cache_evictions: counter(
+                "cache_evictions_total",
+                "Entries removed from the cache, by cause (expired, size, explicit)",
+                &["template", "cause"],
+            ),
+            cache_entries: gauge("cache_entries", "Entries in the cache"),
+            cache_weighted_size: gauge("cache_weighted_size_bytes", "Size of the cached results"),
+            query_duration,
+            pool_connections: gauge_vec(
+                "pool_connections",
+                "Pooled database connections, by state (in_use, idle)",
+                &["database", "pool", "state"],
+            ),
+            pool_waiting: gauge_vec(
+                "pool_waiting",
+                "Queries waiting for a pooled connection",
+                &["pool"],
+            ),
+            upstream_errors: counter(
+                "upstream_errors_total",
+                "Database errors, by SQLSTATE class or connection_error / pool_timeout",
+                &["class"],
+            ),
+            http_requests: counter(
+                "http_requests_total",
+                "HTTP requests, by route and status",
+                &["route", "status"],
+            ),
+            registry,
+        }
+    }
+
+    pub fn observe_query(&self, hit: bool, seconds: f64) {
+        let cache = if hit { "hit" } else { "miss" };
+        self.query_duration
+            .with_label_values(&[cache])
+            .observe(seconds);
+    }
+
+    pub fn record_eviction(&self, template: &str, cause: RemovalCause) {
+        let cause = match cause {
+            RemovalCause::Expired => "expired",
+            RemovalCause::Explicit => "explicit",
+            RemovalCause::Size => "size",
+            RemovalCause::Replaced => return, // Refreshed, not evicted
+        };
+        self.cache_evictions
+            .with_label_values(&[template, cause])
+            .inc();
+    }
+
+    pub fn record_upstream_error(&self, error: &PledgeError) {
+        // SQLSTATEs are five characters, the class is the first two
+        let class = match error.body.code.len() {
+            5 => &error.body.code[..2],
+            _ => error.body.code.as_str(),
+        };
+        self.upstream_errors.with_label_values(&[class]).inc();
+    }
+
+    // Counts the caller as waiting until the returned guard is dropped
+    pub fn waiting_for(&self, pool: &PgPool) -> WaitingGuard {
+        let gauge = self.pool_waiting.with_label_values(&[&pool::label(pool)]);
+        gauge.inc();
+        WaitingGuard { gauge }
+    }
+}
+
+pub struct WaitingGuard {
+    gauge: IntGauge,
+}
+
+impl Drop for WaitingGuard {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+// Counts every routed request, including the ones rejected by authentication
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let response = next.run(request).await;
+    METRICS
+        .http_requests
+        .with_label_values(&[&route, response.status().as_str()])
+        .inc();
+    response
+}
+
+// GET /metrics in the Prometheus text format, admin only. Gauges that can be read directly are
+// updated here.
+pub async fn metrics_handler(
+    State(state): State<AppState>,
+    identity: Option<Extension<Arc<Identity>>>,
+) -> Result<Response, PledgeError> {
+    auth::require_admin(identity.as_deref().map(Arc::as_ref))?;
+    let metrics = &METRICS;
+
+    state.cache.run_pending_tasks();
+    metrics.cache_entries.set(state.cache.entry_count() as i64);
+    metrics
+        .cache_weighted_size
+        .set(state.cache.weighted_size() as i64);
+
+    for upstream in state.databases.iter() {
+        let pools = std::iter::once(upstream.writer())
+            .chain(upstream.replicas().iter().map(|replica| &replica.pool));
+        for pool in pools {
+            let label = pool::label(pool);
+            let idle = pool.num_idle() as i64;
+            let in_use = pool.size() as i64 - idle;
+            for (state, value) in [("in_use", in_use), ("idle", idle)] {
+                metrics
+                    .pool_connections
+                    .with_label_values(&[upstream.name.as_str(), &label, state])
+                    .set(value);
+            }
+        }
+    }
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&metrics.registry.gather(), &mut buffer)
+        .map_err(|e| PledgeError::internal(e.to_string()))?;
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        buffer,
+    )
+        .into_response())
+}
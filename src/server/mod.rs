@@ -1,94 +1,222 @@
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::time::Duration;
 
 use axum::Router;
+use axum::http::{HeaderMap, StatusCode, Uri, header};
+use axum::middleware;
+use axum::response::{IntoResponse, Redirect, Response};
 use axum::routing::{get, post};
-use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
 use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
 
 use crate::AppState;
-use crate::config::ServerConfig;
-use crate::handlers::{health::health_handler, query::query_handler};
+use crate::auth;
+use crate::config::{HttpMode, ServerConfig};
+use crate::handlers::{
+    batch::batch_handler,
+    health::{health_handler, ready_handler},
+    query::query_handler,
+    stats::{suggestions_handler, template_stats_handler},
+    transaction::transaction_handler,
+};
+use crate::logging;
+use crate::metrics::{self, metrics_handler};
+use shutdown::Shutdown;
+use tls::{ClientCertAcceptor, TlsFiles};
+pub mod shutdown;
 pub mod state;
+pub mod tls;
 
 pub fn create_router(state: AppState) -> Router {
-    Router::new()
-        .route("/health", get(health_handler))
+    let protected = Router::new()
         .route("/query", post(query_handler))
+        .route("/batch", post(batch_handler))
+        .route("/transaction", post(transaction_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/stats/templates", get(template_stats_handler))
+        .route("/stats/suggestions", get(suggestions_handler))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
+        ));
+
+    health_routes()
+        .merge(protected)
+        .route_layer(middleware::from_fn(metrics::track_requests))
+        .route_layer(middleware::from_fn(logging::trace_requests))
         .with_state(state)
 }
 
+fn health_routes() -> Router<AppState> {
+    Router::new()
+        .route("/health", get(health_handler))
+        .route("/health/live", get(health_handler))
+        .route("/health/ready", get(ready_handler))
+}
+
+// Router for the plain HTTP port, http_mode only applies once HTTPS is configured
+fn http_router(mode: HttpMode, https_port: u16, state: AppState) -> Router {
+    match mode {
+        HttpMode::Full => create_router(state),
+        HttpMode::HealthOnly => health_routes().with_state(state),
+        HttpMode::Redirect => {
+            Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+                redirect_to_https(&headers, &uri, https_port)
+            })
+        }
+    }
+}
+
+fn redirect_to_https(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Response {
+    let Some(host) = headers.get(header::HOST).and_then(|h| h.to_str().ok()) else {
+        return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+    };
+    // Drop the HTTP port, but not the colons of a bracketed IPv6 address
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    let location = match https_port {
+        443 => format!("https://{}{}", host, path),
+        port => format!("https://{}:{}{}", host, port, path),
+    };
+    Redirect::permanent(&location).into_response()
+}
+
+// Serves until SIGTERM or SIGINT, then stops accepting connections and lets in-flight requests
+// finish for up to shutdown_timeout_secs
 pub async fn run_server(server_config: &ServerConfig, state: AppState) {
-    let routes = create_router(state);
-    let cloned_routes = routes.clone();
     let port = server_config.port;
+    let shutdown = Shutdown::on_signal();
+    let drain_timeout = Duration::from_secs(server_config.shutdown_timeout_secs.unwrap_or(30));
+    let tls_files = match (&server_config.tls_cert_path, &server_config.tls_key_path) {
+        (Some(cert), Some(key)) => Some(TlsFiles {
+            cert: cert.clone(),
+            key: key.clone(),
+            client_ca: server_config.tls_client_ca_path.clone(),
+        }),
+        _ => None,
+    };
+    let reload_interval = Duration::from_secs(server_config.tls_reload_interval_secs.unwrap_or(10));
 
+    // load_config makes sure http_mode is only set along with HTTPS
+    let routes = match server_config.https_port {
+        Some(https_port) => http_router(server_config.http_mode, https_port, state.clone()),
+        None => create_router(state.clone()),
+    };
+    let cloned_routes = create_router(state);
+
+    let http_shutdown = shutdown.clone();
     let http_handle = tokio::spawn(async move {
         let listener = match tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port)).await {
             Ok(listener) => {
-                println!("Server listening on HTTP on port {}", port);
+                info!("Server listening on HTTP on port {}", port);
                 listener
             }
             Err(err) => {
-                eprintln!("Failed to bind to port {}: {}", port, err);
+                error!("Failed to bind to port {}: {}", port, err);
                 return;
             }
         };
 
-        match axum::serve(listener, routes).await {
+        match axum::serve(listener, routes)
+            .with_graceful_shutdown(http_shutdown.triggered())
+            .await
+        {
             Ok(_) => {}
             Err(err) => {
-                eprintln!("Failed to serve HTTP server: {}", err);
+                error!("Failed to serve HTTP server: {}", err);
             }
         };
     });
 
-    let https_handle: Option<JoinHandle<()>> = if let (Some(https_port), Some(cert), Some(key)) = (
-        server_config.https_port,
-        server_config.tls_cert_path.clone(),
-        server_config.tls_key_path.clone(),
-    ) && https_port != port
+    let https_shutdown = shutdown.clone();
+    let https_handle: Option<JoinHandle<()>> = if let (Some(https_port), Some(tls_files)) =
+        (server_config.https_port, tls_files)
+        && https_port != port
     {
         Some(tokio::spawn(async move {
-            let config =
-                match RustlsConfig::from_pem_file(PathBuf::from(cert), PathBuf::from(key)).await {
-                    Ok(config) => {
-                        println!("Server listening on HTTPS on port {}", https_port);
-                        config
-                    }
-                    Err(err) => {
-                        eprintln!("Failed to load TLS certificate and key: {}", err);
-                        return;
-                    }
-                };
+            let handle = Handle::new();
+            let drain_handle = handle.clone();
+            tokio::spawn(async move {
+                https_shutdown.triggered().await;
+                drain_handle.graceful_shutdown(Some(drain_timeout));
+            });
+
+            let client_ca = tls_files.client_ca.is_some();
+            let config = match tls_files.load().await {
+                Ok(config) => {
+                    info!(
+                        "Server listening on HTTPS on port {}{}",
+                        https_port,
+                        if client_ca {
+                            " with client certificates"
+                        } else {
+                            ""
+                        }
+                    );
+                    config
+                }
+                Err(err) => {
+                    error!("Failed to load TLS certificate and key: {}", err);
+                    return;
+                }
+            };
+
+            tls_files.spawn_reload(config.clone(), reload_interval);
 
             let addr = SocketAddr::from(([0, 0, 0, 0], https_port));
-            match axum_server::bind_rustls(addr, config)
-                .serve(cloned_routes.into_make_service())
-                .await
-            {
+            let served = match client_ca {
+                true => {
+                    axum_server::bind(addr)
+                        .handle(handle)
+                        .acceptor(ClientCertAcceptor::new(config))
+                        .serve(cloned_routes.into_make_service())
+                        .await
+                }
+                false => {
+                    axum_server::bind_rustls(addr, config)
+                        .handle(handle)
+                        .serve(cloned_routes.into_make_service())
+                        .await
+                }
+            };
+            match served {
                 Ok(_) => {}
-                Err(err) => eprintln!("Failed to start HTTPS server: {}", err),
+                Err(err) => error!("Failed to start HTTPS server: {}", err),
             };
         }))
     } else {
         None
     };
 
-    match https_handle {
-        Some(https_handle) => {
-            let (http, https) = tokio::join!(http_handle, https_handle);
-            match http {
-                Ok(_) => {}
-                Err(err) => eprintln!("{}", err),
-            };
-            match https {
-                Ok(_) => {}
-                Err(err) => eprintln!("{}", err),
-            };
-        }
-        None => {
-            let _ = http_handle.await;
+    let served = async {
+        match https_handle {
+            Some(https_handle) => {
+                let (http, https) = tokio::join!(http_handle, https_handle);
+                match http {
+                    Ok(_) => {}
+                    Err(err) => error!("{}", err),
+                };
+                match https {
+                    Ok(_) => {}
+                    Err(err) => error!("{}", err),
+                };
+            }
+            None => {
+                let _ = http_handle.await;
+            }
         }
+    };
+    // axum::serve has no deadline of its own for draining
+    let deadline = async {
+        shutdown.triggered().await;
+        tokio::time::sleep(drain_timeout).await;
+    };
+    tokio::select! {
+        _ = served => info!("All in-flight requests finished"),
+        _ = deadline => warn!("Shutdown timeout reached, dropping in-flight requests"),
     }
 }
@@ -0,0 +1,48 @@
+use tokio::signal;
+use tokio::sync::watch;
+use tracing::info;
+
+// Shared shutdown flag, set once on SIGTERM or SIGINT
+#[derive(Clone)]
+pub struct Shutdown {
+    rx: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    pub fn on_signal() -> Self {
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            info!("Shutting down, no longer accepting connections");
+            let _ = tx.send(true);
+        });
+        Shutdown { rx }
+    }
+
+    pub async fn triggered(mut self) {
+        let _ = self.rx.wait_for(|shutdown| *shutdown).await;
+    }
+}
+
+async fn wait_for_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("Failed to install SIGINT handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
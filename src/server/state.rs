@@ -1,12 +1,28 @@
-use std::{sync::Arc, time::Instant};
+use std::sync::Arc;
+use std::time::Duration;
 
 use crate::QueryMatcher;
-use sqlx::PgPool;
+use crate::auth::Authenticator;
+use crate::cache::auto::AutoCache;
+use crate::cache::store::CacheEntry;
+use crate::config::LimitsConfig;
+use crate::database::registry::Databases;
+use crate::stats::{QueryFingerprints, TemplateStats};
 
 #[derive(Clone)]
 pub struct AppState {
-    pub pool: Arc<PgPool>,
+    pub databases: Arc<Databases>,
     pub matcher: Arc<QueryMatcher>,
-    pub cache: Arc<moka::sync::Cache<String, (Vec<u8>, Instant)>>, // First value (Vec<u8>) is the serialized response, second value (Instant) is the expiration time
+    pub cache: Arc<moka::sync::Cache<String, CacheEntry>>,
     pub global_ttl: u64,
+    pub query_timeout_ms: Option<u64>,
+    pub ready_when_degraded: bool,
+    pub limits: LimitsConfig,
+    pub max_entry_bytes: Option<usize>,
+    pub auth: Arc<Authenticator>,
+    pub log_params: bool,
+    pub slow_query: Option<Duration>,
+    pub stats: Arc<TemplateStats>,
+    pub fingerprints: Arc<QueryFingerprints>,
+    pub auto_cache: Option<Arc<AutoCache>>,
 }
@@ -0,0 +1,178 @@
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use axum::Extension;
+use axum_server::accept::{Accept, DefaultAcceptor};
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use rustls::RootCertStore;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+use tracing::{info, warn};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+// The PEM files the HTTPS listener is configured from, kept around to reload them
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: String,
+    pub key: String,
+    pub client_ca: Option<String>, // Verify client certificates against this CA when set
+}
+
+impl TlsFiles {
+    pub async fn load(&self) -> io::Result<RustlsConfig> {
+        match &self.client_ca {
+            Some(client_ca) => Ok(RustlsConfig::from_config(client_ca_config(
+                &self.cert, &self.key, client_ca,
+            )?)),
+            None => RustlsConfig::from_pem_file(&self.cert, &self.key).await,
+        }
+    }
+
+    // Swaps in the new certificate for new handshakes, open connections keep the one they started with
+    async fn reload(&self, config: &RustlsConfig) -> io::Result<()> {
+        match &self.client_ca {
+            Some(client_ca) => {
+                config.reload_from_config(client_ca_config(&self.cert, &self.key, client_ca)?);
+                Ok(())
+            }
+            None => config.reload_from_pem_file(&self.cert, &self.key).await,
+        }
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    // Polls the files' modification times and reloads them when any changed. A failed reload
+    // (e.g. the key was replaced before the certificate) keeps the old config and is retried.
+    pub fn spawn_reload(self, config: RustlsConfig, interval: Duration) {
+        tokio::spawn(async move {
+            let mut loaded = self.modified();
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let modified = self.modified();
+                if modified == loaded {
+                    continue;
+                }
+                match self.reload(&config).await {
+                    Ok(()) => {
+                        info!("Reloaded TLS certificate from {}", self.cert);
+                        loaded = modified;
+                    }
+                    Err(err) => warn!("Failed to reload TLS certificate: {}", err),
+                }
+            }
+        });
+    }
+}
+
+fn client_ca_config(
+    cert: &str,
+    key: &str,
+    client_ca: &str,
+) -> io::Result<Arc<rustls::ServerConfig>> {
+    let mut roots = RootCertStore::empty();
+    for ca in CertificateDer::pem_file_iter(client_ca).map_err(io::Error::other)? {
+        roots
+            .add(ca.map_err(io::Error::other)?)
+            .map_err(io::Error::other)?;
+    }
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .build()
+        .map_err(io::Error::other)?;
+
+    let chain = CertificateDer::pem_file_iter(cert)
+        .map_err(io::Error::other)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(io::Error::other)?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(io::Error::other)?;
+
+    let mut config = rustls::ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(chain, key)
+        .map_err(io::Error::other)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+// Names from the verified client certificate of the connection a request came in on
+#[derive(Debug, Clone, Default)]
+pub struct ClientCert {
+    pub names: Vec<String>, // Subject CN, then the DNS, URI and email SANs
+}
+
+impl ClientCert {
+    fn from_der(der: &[u8]) -> Self {
+        let Ok((_, cert)) = X509Certificate::from_der(der) else {
+            return ClientCert::default();
+        };
+
+        let mut names: Vec<String> = cert
+            .subject()
+            .iter_common_name()
+            .filter_map(|cn| cn.as_str().ok())
+            .map(str::to_string)
+            .collect();
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::URI(name)
+                    | GeneralName::RFC822Name(name) => names.push(name.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        ClientCert { names }
+    }
+}
+
+// Completes the TLS handshake, then hands the connection's client certificate to every request on it
+#[derive(Clone)]
+pub struct ClientCertAcceptor {
+    inner: RustlsAcceptor<DefaultAcceptor>,
+}
+
+impl ClientCertAcceptor {
+    pub fn new(config: RustlsConfig) -> Self {
+        ClientCertAcceptor {
+            inner: RustlsAcceptor::new(config),
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = <Extension<ClientCert> as Layer<S>>::Service;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let cert = match stream.get_ref().1.peer_certificates() {
+                Some([leaf, ..]) => ClientCert::from_der(leaf),
+                _ => ClientCert::default(),
+            };
+            Ok((stream, Extension(cert).layer(service)))
+        })
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::database::statement;
+
+// Upstream latencies and cache TTLs kept per template, reports are taken over these recent samples
+const SAMPLES: usize = 1024;
+// Once this many are tracked, the least recently seen fingerprint makes room. Evicting by database
+// time instead would keep dropping new fingerprints before they could add up any.
+const MAX_FINGERPRINTS: usize = 1000;
+const MAX_SUGGESTED_TTL: u64 = 300;
+const DEFAULT_SUGGESTED_TTL: u64 = 60; // Until a call was repeated with the same params
+
+// Per-template counters of queries answered by run_query, served on GET /stats/templates
+#[derive(Default)]
+pub struct TemplateStats {
+    templates: Mutex<HashMap<String, Counters>>,
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: u64,
+    fetches: u64,
+    fetched_bytes: u64,
+    bytes_saved: u64,
+    latencies_ms: Samples<f64>,
+    ttls_secs: Samples<u64>, // Adaptive TTLs differ between parameter sets, so a range is reported
+}
+
+// Ring buffer of the last SAMPLES values
+struct Samples<T> {
+    values: Vec<T>,
+    next: usize,
+}
+
+impl<T> Default for Samples<T> {
+    fn default() -> Self {
+        Samples {
+            values: Vec::new(),
+            next: 0,
+        }
+    }
+}
+
+impl<T> Samples<T> {
+    fn push(&mut self, value: T) {
+        match self.values.len() < SAMPLES {
+            true => self.values.push(value),
+            false => self.values[self.next] = value,
+        }
+        self.next = (self.next + 1) % SAMPLES;
+    }
+}
+
+#[derive(Serialize)]
+pub struct TemplateReport {
+    pub name: String,
+    pub calls: u64,
+    pub hits: u64,
+    pub hit_ratio: f64,
+    pub p50_ms: Option<f64>, // Upstream execution time, None until the template was fetched once
+    pub p95_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+    pub avg_result_bytes: u64,
+    pub bytes_saved: u64, // Result bytes served from the cache instead of the database
+    pub ttl_min_secs: Option<u64>, // TTLs given to the last SAMPLES cached results, None until one was cached
+    pub ttl_avg_secs: Option<u64>,
+    pub ttl_max_secs: Option<u64>,
+}
+
+impl TemplateStats {
+    pub fn record_hit(&self, template: &str, bytes: usize) {
+        let mut templates = self.templates.lock().unwrap();
+        let counters = templates.entry(template.to_string()).or_default();
+        counters.hits += 1;
+        counters.bytes_saved += bytes as u64;
+    }
+
+    pub fn record_fetch(&self, template: &str, elapsed: Duration, bytes: usize) {
+        let mut templates = self.templates.lock().unwrap();
+        let counters = templates.entry(template.to_string()).or_default();
+        counters.fetches += 1;
+        counters.fetched_bytes += bytes as u64;
+        counters.latencies_ms.push(elapsed.as_secs_f64() * 1000.0);
+    }
+
+    pub fn record_ttl(&self, template: &str, ttl: Duration) {
+        let mut templates = self.templates.lock().unwrap();
+        let counters = templates.entry(template.to_string()).or_default();
+        counters.ttls_secs.push(ttl.as_secs());
+    }
+
+    // One report per name, templates that were never called are reported with zeroes
+    pub fn report<'a>(&self, names: impl Iterator<Item = &'a str>) -> Vec<TemplateReport> {
+        let templates = self.templates.lock().unwrap();
+        let empty = Counters::default();
+        let mut reports: Vec<TemplateReport> = names
+            .map(|name| {
+                let counters = templates.get(name).unwrap_or(&empty);
+                let calls = counters.hits + counters.fetches;
+                let mut latencies = counters.latencies_ms.values.clone();
+                let ttls = &counters.ttls_secs.values;
+                latencies.sort_by(f64::total_cmp);
+                TemplateReport {
+                    name: name.to_string(),
+                    calls,
+                    hits: counters.hits,
+                    hit_ratio: match calls {
+                        0 => 0.0,
+                        calls => counters.hits as f64 / calls as f64,
+                    },
+                    p50_ms: percentile(&latencies, 0.50),
+                    p95_ms: percentile(&latencies, 0.95),
+                    p99_ms: percentile(&latencies, 0.99),
+                    avg_result_bytes: (counters.fetched_bytes + counters.bytes_saved)
+                        .checked_div(calls)
+                        .unwrap_or(0),
+                    bytes_saved: counters.bytes_saved,
+                    ttl_min_secs: ttls.iter().min().copied(),
+                    ttl_avg_secs: ttls.iter().sum::<u64>().checked_div(ttls.len() as u64),
+                    ttl_max_secs: ttls.iter().max().copied(),
+                }
+            })
+            .collect();
+        reports.sort_by(|a, b| b.calls.cmp(&a.calls).then_with(|| a.name.cmp(&b.name)));
+        reports
+    }
+}
+
+// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[f64], quantile: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+// Read-only SQL that matched no template, by database and fingerprint. Served on
+// GET /stats/suggestions as candidates for new templates.
+#[derive(Default)]
+pub struct QueryFingerprints {
+    queries: Mutex<HashMap<(String, String), FingerprintCounters>>,
+}
+
+struct FingerprintCounters {
+    sql: String, // Last text seen, what a template has to match exactly
+    has_literals: bool,
+    calls: u64,
+    total_ms: f64,
+    max_ms: f64,
+    total_bytes: u64,
+    first_seen: Instant,
+    last_seen: Instant,
+    last_call: Option<LastCall>,
+    shortest_change: Option<Duration>, // Shortest time in which a repeated call's result changed
+    unchanged_repeats: u64,
+}
+
+// Repeating the same statement with the same params shows whether its result changed in between
+struct LastCall {
+    call: u64, // Hash of the SQL text and params
+    etag: String,
+    at: Instant,
+}
+
+pub struct FingerprintUsage {
+    pub calls: u64,
+    pub avg_ms: f64, // Average time in the database
+}
+
+#[derive(Serialize)]
+pub struct Suggestion {
+    pub name: String,
+    pub database: String,
+    pub sql: String,
+    pub calls: u64,
+    pub calls_per_min: f64,
+    pub avg_ms: f64,
+    pub max_ms: f64,
+    pub db_time_ms: f64, // Total time spent in the database, suggestions are ranked by it
+    pub avg_result_bytes: u64,
+    pub suggested_ttl: u64,
+    pub ttl_basis: &'static str, // "changed", "unchanged" or "no_repeats"
+    pub has_literals: bool,      // Literals must become $n parameters before a template can match
+}
+
+impl QueryFingerprints {
+    // Returns the fingerprint's usage so far, including this call
+    pub fn record(
+        &self,
+        database: &str,
+        sql: &str,
+        params: &[serde_json::Value],
+        elapsed: Duration,
+        bytes: usize,
+        etag: &str,
+    ) -> FingerprintUsage {
+        let fingerprint = statement::fingerprint(sql);
+        let key = (database.to_string(), fingerprint.text);
+        let now = Instant::now();
+        let mut queries = self.queries.lock().unwrap();
+        if !queries.contains_key(&key)
+            && queries.len() >= MAX_FINGERPRINTS
+            && let Some(stalest) = queries
+                .iter()
+                .min_by_key(|(_, counters)| counters.last_seen)
+                .map(|(key, _)| key.clone())
+        {
+            queries.remove(&stalest);
+        }
+        let counters = queries.entry(key).or_insert_with(|| FingerprintCounters {
+            sql: String::new(),
+            has_literals: false,
+            calls: 0,
+            total_ms: 0.0,
+            max_ms: 0.0,
+            total_bytes: 0,
+            first_seen: now,
+            last_seen: now,
+            last_call: None,
+            shortest_change: None,
+            unchanged_repeats: 0,
+        });
+
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        counters.sql = sql.to_string();
+        counters.has_literals = fingerprint.has_literals;
+        counters.last_seen = now;
+        counters.calls += 1;
+        counters.total_ms += ms;
+        counters.max_ms = counters.max_ms.max(ms);
+        counters.total_bytes += bytes as u64;
+
+        // With inline literals the same fingerprint covers different statements
+        let call = hash(&format!(
+            "{}\0{}",
+            sql,
+            serde_json::Value::from(params.to_vec())
+        ));
+        if let Some(last) = &counters.last_call
+            && last.call == call
+        {
+            match last.etag == etag {
+                true => counters.unchanged_repeats += 1,
+                false => {
+                    let within = now.duration_since(last.at);
+                    counters.shortest_change =
+                        Some(counters.shortest_change.map_or(within, |d| d.min(within)));
+                }
+            }
+        }
+        counters.last_call = Some(LastCall {
+            call,
+            etag: etag.to_string(),
+            at: now,
+        });
+        FingerprintUsage {
+            calls: counters.calls,
+            avg_ms: counters.total_ms / counters.calls as f64,
+        }
+    }
+
+    // The fingerprints that took the most database time, called at least min_calls times
+    pub fn suggest(&self, min_calls: u64, limit: usize) -> Vec<Suggestion> {
+        let queries = self.queries.lock().unwrap();
+        let mut suggestions: Vec<Suggestion> = queries
+            .iter()
+            .filter(|(_, counters)| counters.calls >= min_calls)
+            .map(|((database, fingerprint), counters)| {
+                // A result that changed within some time is cached for at most half of it
+                let (suggested_ttl, ttl_basis) = match counters.shortest_change {
+                    Some(within) => (
+                        (within.as_secs() / 2).clamp(1, MAX_SUGGESTED_TTL),
+                        "changed",
+                    ),
+                    None if counters.unchanged_repeats > 0 => (MAX_SUGGESTED_TTL, "unchanged"),
+                    None => (DEFAULT_SUGGESTED_TTL, "no_repeats"),
+                };
+                let minutes = counters.first_seen.elapsed().as_secs_f64() / 60.0;
+                Suggestion {
+                    name: format!("query_{:08x}", hash(fingerprint) as u32),
+                    database: database.clone(),
+                    sql: counters.sql.clone(),
+                    calls: counters.calls,
+                    calls_per_min: counters.calls as f64 / minutes.max(1.0),
+                    avg_ms: counters.total_ms / counters.calls as f64,
+                    max_ms: counters.max_ms,
+                    db_time_ms: counters.total_ms,
+                    avg_result_bytes: counters.total_bytes / counters.calls,
+                    suggested_ttl,
+                    ttl_basis,
+                    has_literals: counters.has_literals,
+                }
+            })
+            .collect();
+        suggestions.sort_by(|a, b| b.db_time_ms.total_cmp(&a.db_time_ms));
+        suggestions.truncate(limit);
+        suggestions
+    }
+}
+
+fn hash(value: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_the_range_of_recent_ttls() {
+        let stats = TemplateStats::default();
+        for secs in [4, 8, 30] {
+            stats.record_ttl("adaptive_user", Duration::from_secs(secs));
+        }
+        let reports = stats.report(["adaptive_user", "get_user"].into_iter());
+        let adaptive = reports.iter().find(|r| r.name == "adaptive_user").unwrap();
+        assert_eq!(adaptive.ttl_min_secs, Some(4));
+        assert_eq!(adaptive.ttl_avg_secs, Some(14));
+        assert_eq!(adaptive.ttl_max_secs, Some(30));
+        let never_cached = reports.iter().find(|r| r.name == "get_user").unwrap();
+        assert_eq!(never_cached.ttl_avg_secs, None);
+    }
+
+    #[test]
+    fn samples_keep_the_most_recent_values() {
+        let mut samples = Samples::default();
+        for value in 0..SAMPLES as u64 + 10 {
+            samples.push(value);
+        }
+        assert_eq!(samples.values.len(), SAMPLES);
+        assert_eq!(samples.values.iter().min(), Some(&10));
+    }
+}
@@ -0,0 +1,59 @@
+use axum::http::HeaderMap;
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, SdkTracer, SdkTracerProvider};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::TelemetryConfig;
+
+// Builds the OTLP/HTTP exporter. Spans are batched and sent from a background thread.
+pub fn init(
+    config: &TelemetryConfig,
+) -> Result<(SdkTracerProvider, SdkTracer), Box<dyn std::error::Error>> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&config.endpoint)
+        .build()?;
+    let sampler = match config.sample_ratio {
+        Some(ratio) => Sampler::TraceIdRatioBased(ratio),
+        None => Sampler::AlwaysOn,
+    };
+    let service_name = config.service_name.as_deref().unwrap_or("pledge");
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        // Callers that already decided whether to sample a trace are followed
+        .with_sampler(Sampler::ParentBased(Box::new(sampler)))
+        .with_resource(
+            Resource::builder()
+                .with_service_name(service_name.to_string())
+                .build(),
+        )
+        .build();
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = provider.tracer("pledge");
+    Ok((provider, tracer))
+}
+
+// Continues the caller's trace from its `traceparent` header, if it sent one
+pub fn continue_trace(span: &Span, headers: &HeaderMap) {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    let _ = span.set_parent(parent);
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}